@@ -0,0 +1,121 @@
+//! Benchmarks of the whole pipeline — parsing, label generation and formatting — on big buffers.
+//!
+//! The binary is run the way Kakoune runs it, so that the benchmarks measure what users actually wait for.
+
+use std::{
+  io::Write,
+  process::{Command, Stdio},
+  thread,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const KEYSET: &str = "fjdkslaghrueiwoqptyvmcnxbz";
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+/// Run the binary with `args`, writing `input` on its standard input, and return its standard output.
+fn hop(args: &[&str], input: &str) -> Vec<u8> {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_hop-kak"))
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .expect("cannot run hop-kak");
+  let mut stdin = child.stdin.take().expect("no stdin");
+
+  // write and read at the same time, so that neither side blocks on a full pipe
+  let output = thread::scope(|scope| {
+    scope.spawn(move || stdin.write_all(input.as_bytes()));
+    child.wait_with_output().expect("hop-kak failed")
+  });
+
+  assert!(output.status.success());
+  output.stdout
+}
+
+/// Buffer text made of `count` words, ten per line.
+fn text(count: usize) -> String {
+  (0..count)
+    .map(|i| {
+      let sep = if i % 10 == 9 { '\n' } else { ' ' };
+      format!("word_{i}{sep}")
+    })
+    .collect()
+}
+
+/// `count` single-character selections, ten per line.
+fn sels(count: usize) -> String {
+  let sels: Vec<_> = (0..count)
+    .map(|i| {
+      let (line, col) = (i / 10 + 1, i % 10 * 8 + 1);
+      format!("{line}.{col},{line}.{col}")
+    })
+    .collect();
+  sels.join(" ")
+}
+
+/// `count` labels of four keys, so that they are prefix-free.
+fn labels(count: usize) -> String {
+  let keys: Vec<_> = KEYSET.chars().collect();
+  let labels: Vec<String> = (0..count)
+    .map(|i| {
+      (0..4)
+        .rev()
+        .map(|digit| keys[i / keys.len().pow(digit) % keys.len()])
+        .collect()
+    })
+    .collect();
+  labels.join(" ")
+}
+
+fn words(c: &mut Criterion) {
+  let mut group = c.benchmark_group("words");
+
+  for size in SIZES {
+    let text = text(size);
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &text, |b, text| {
+      b.iter(|| hop(&["label", "--keyset", KEYSET, "--words"], text))
+    });
+  }
+
+  group.finish();
+}
+
+fn generate(c: &mut Criterion) {
+  let mut group = c.benchmark_group("generate");
+
+  for size in SIZES {
+    let sels = sels(size);
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &sels, |b, sels| {
+      b.iter(|| hop(&["label", "--keyset", KEYSET, "--sels", "-"], sels))
+    });
+  }
+
+  group.finish();
+}
+
+fn reduce(c: &mut Criterion) {
+  let mut group = c.benchmark_group("reduce");
+
+  for size in SIZES {
+    let input = format!("{}\n{}\n", sels(size), labels(size));
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+      b.iter(|| {
+        hop(
+          &[
+            "reduce", "--keyset", KEYSET, "--sels", "-", "--labels", "-", "--key", "f",
+          ],
+          input,
+        )
+      })
+    });
+  }
+
+  group.finish();
+}
+
+criterion_group!(benches, words, generate, reduce);
+criterion_main!(benches);
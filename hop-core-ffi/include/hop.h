@@ -0,0 +1,73 @@
+/* C bindings of hop-core, the label engine of hop-kak.
+ *
+ * Labels are generated with hop_generate_labels and reduced, one key at a time, with hop_reduce. Both return a
+ * HopHints describing the state of the hop, which must be freed with hop_hints_free. Strings returned by the hop_hints_*
+ * accessors are owned by the HopHints they come from. */
+
+#ifndef HOP_H
+#define HOP_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* State of a hop. */
+typedef enum HopStatus {
+  /* Labels are displayed, waiting for the next key. */
+  HOP_STATUS_HINTS,
+
+  /* The hop resolved to its targets. */
+  HOP_STATUS_JUMP,
+
+  /* The hop was cancelled. */
+  HOP_STATUS_CANCEL,
+
+  /* The hop cannot start; see hop_hints_message. */
+  HOP_STATUS_FAIL,
+} HopStatus;
+
+/* Hints of a hop. */
+typedef struct HopHints HopHints;
+
+/* Generate labels with the keys of keyset for sels, space separated Kakoune selections such as "1.1,1.5 3.2,3.4".
+ *
+ * A single selection is jumped to right away. Return NULL if an argument is NULL or not UTF-8. */
+HopHints *hop_generate_labels(const char *keyset, const char *sels);
+
+/* Reduce hints with key, as reported by Kakoune in $kak_key, such as "a", "<backspace>" or "<esc>".
+ *
+ * hints is left untouched. Return NULL if an argument is NULL or not UTF-8, or if hints is not waiting for a key. */
+HopHints *hop_reduce(const HopHints *hints, const char *key);
+
+/* State of hints; a NULL hints is reported as failed. */
+HopStatus hop_hints_status(const HopHints *hints);
+
+/* Number of remaining hints, or of targets if the hop resolved. */
+size_t hop_hints_count(const HopHints *hints);
+
+/* Selection of the i-th remaining hint, or i-th target if the hop resolved, the first one being the main one.
+ *
+ * Return NULL if i is out of bounds. */
+const char *hop_hints_sel(const HopHints *hints, size_t i);
+
+/* Full label of the i-th remaining hint, including the keys typed so far.
+ *
+ * Return NULL if i is out of bounds or the hop is not waiting for a key. */
+const char *hop_hints_label(const HopHints *hints, size_t i);
+
+/* Keys typed so far. */
+const char *hop_hints_typed(const HopHints *hints);
+
+/* Why the hop cannot start, or an empty string if it did not fail. */
+const char *hop_hints_message(const HopHints *hints);
+
+/* Free hints, and the strings it owns. */
+void hop_hints_free(HopHints *hints);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* HOP_H */
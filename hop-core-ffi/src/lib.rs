@@ -0,0 +1,416 @@
+//! C bindings of hop-core, so that editors and terminal multiplexers written in C can embed the hints instead of running
+//! hop-kak.
+//!
+//! Labels are generated with [`hop_generate_labels`] and reduced, one key at a time, with [`hop_reduce`]. Both return
+//! a [`HopHints`] describing the state of the hop, which must be freed with [`hop_hints_free`]. The declarations are in
+//! `include/hop.h`.
+
+use std::{
+  ffi::{c_char, CStr, CString},
+  panic, ptr,
+};
+
+use clap::{Args, Command, FromArgMatches};
+use hop_core::{
+  Action, App, Input, LabelOptions, Options, ReduceOptions, ReplaceRange, Response, STALE_MESSAGE,
+};
+
+/// State of a hop.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HopStatus {
+  /// Labels are displayed, waiting for the next key.
+  Hints,
+
+  /// The hop resolved to its targets.
+  Jump,
+
+  /// The hop was cancelled.
+  Cancel,
+
+  /// The hop cannot start; see [`hop_hints_message`].
+  Fail,
+}
+
+/// Hints of a hop, as returned by [`hop_generate_labels`] and [`hop_reduce`].
+#[derive(Debug)]
+pub struct HopHints {
+  status: HopStatus,
+  keyset: String,
+
+  /// All the hinted ranges, with their full labels, to reduce them further.
+  replace_ranges: Vec<ReplaceRange>,
+
+  /// Keys typed so far.
+  typed: CString,
+
+  /// Selections of the remaining hints, or targets of the jump.
+  sels: Vec<CString>,
+
+  /// Full labels of the remaining hints.
+  labels: Vec<CString>,
+
+  /// Why the hop cannot start, if it failed.
+  message: CString,
+}
+
+impl HopHints {
+  /// Run a hop with the keys of `keyset` and the `label` or `reduce` arguments `args`, restoring `replace_ranges` if any.
+  fn run<A>(keyset: &str, args: &[String], replace_ranges: Vec<ReplaceRange>) -> Self
+  where
+    A: Args + Into<Action>,
+  {
+    let mut hints = Self {
+      status: HopStatus::Hints,
+      keyset: keyset.to_owned(),
+      replace_ranges: Vec::new(),
+      typed: CString::default(),
+      sels: Vec::new(),
+      labels: Vec::new(),
+      message: CString::default(),
+    };
+
+    let mut args = args.to_vec();
+    args.push(format!("--keyset={keyset}"));
+
+    // unwinding across the C boundary is undefined behavior, so panics fail the hop instead
+    let resp = panic::catch_unwind(|| {
+      let (options, action) = options::<A>(&args)?;
+      let restore = !replace_ranges.is_empty();
+      let mut app = App::new(options, action.into(), Input::Forwarded(Vec::new()));
+      if restore {
+        app.restore(replace_ranges);
+      }
+
+      Ok::<_, String>(app.process())
+    });
+
+    let resp = match resp {
+      Ok(Ok(resp)) => resp,
+      Ok(Err(message)) => {
+        hints.fail(&message);
+        return hints;
+      }
+      Err(_) => {
+        hints.fail("hop-core panicked");
+        return hints;
+      }
+    };
+
+    match resp {
+      Response::LabelsGenerated { replace_ranges, .. } => {
+        hints.display(replace_ranges, String::new())
+      }
+      Response::Reduced {
+        replace_ranges,
+        typed,
+        ..
+      } => hints.display(replace_ranges, typed),
+      // sticky hops are not exposed, so a visit cannot happen
+      Response::Visit { target, .. } => {
+        hints.status = HopStatus::Jump;
+        hints.sels = vec![c_string(target.to_string())];
+      }
+      // opening is up to the caller, so the target is jumped to
+      Response::Open { target, .. } => {
+        hints.status = HopStatus::Jump;
+        hints.sels = vec![c_string(target.to_string())];
+      }
+      Response::Jump { targets, .. } => {
+        hints.status = HopStatus::Jump;
+        hints.sels = targets
+          .iter()
+          .map(|sel| c_string(sel.to_string()))
+          .collect();
+      }
+      Response::Cleanup { .. } => hints.status = HopStatus::Cancel,
+      Response::Stale { .. } => hints.fail(STALE_MESSAGE),
+      Response::Fail { message } => hints.fail(&message),
+      Response::SearchPending { .. } => hints.fail("two-character search is not supported"),
+    }
+
+    hints
+  }
+
+  /// Display the hints which labels start with `typed`.
+  fn display(&mut self, replace_ranges: Vec<ReplaceRange>, typed: String) {
+    for range in &replace_ranges {
+      if range.label().starts_with(&typed) {
+        self.sels.push(c_string(range.sel().to_string()));
+        self.labels.push(c_string(range.label().to_owned()));
+      }
+    }
+
+    self.replace_ranges = replace_ranges;
+    self.typed = c_string(typed);
+  }
+
+  fn fail(&mut self, message: &str) {
+    self.status = HopStatus::Fail;
+    self.message = c_string(message.to_owned());
+  }
+}
+
+/// Options of a hop, parsed from the same command line arguments as hop-kak’s `label` or `reduce`.
+fn options<A: Args>(args: &[String]) -> Result<(Options, A), String> {
+  // `-h` is `--handle`
+  let command = Options::augment_args(
+    Command::new("hop-core-ffi")
+      .no_binary_name(true)
+      .disable_help_flag(true),
+  );
+  A::augment_args(command)
+    .try_get_matches_from(args)
+    .and_then(|matches| {
+      Ok((
+        Options::from_arg_matches(&matches)?,
+        A::from_arg_matches(&matches)?,
+      ))
+    })
+    .map_err(|err| err.to_string())
+}
+
+/// C string of `s`, which never contains NUL bytes, as it comes from C strings.
+fn c_string(s: String) -> CString {
+  CString::new(s).unwrap_or_default()
+}
+
+/// Borrow a C string argument, if not null and valid UTF-8.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string that outlives `'a`.
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+  if s.is_null() {
+    None
+  } else {
+    CStr::from_ptr(s).to_str().ok()
+  }
+}
+
+/// Pointer to a C string of `strings`, or null if `i` is out of bounds.
+fn nth_str(strings: &[CString], i: usize) -> *const c_char {
+  strings.get(i).map_or(ptr::null(), |s| s.as_ptr())
+}
+
+/// Generate labels with the keys of `keyset` for `sels`, space separated Kakoune selections such as `1.1,1.5 3.2,3.4`.
+///
+/// A single selection is jumped to right away. Return null if an argument is null or not UTF-8; the result must be freed
+/// with [`hop_hints_free`].
+///
+/// # Safety
+///
+/// `keyset` and `sels` must be null or point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn hop_generate_labels(
+  keyset: *const c_char,
+  sels: *const c_char,
+) -> *mut HopHints {
+  let (Some(keyset), Some(sels)) = (str_arg(keyset), str_arg(sels)) else {
+    return ptr::null_mut();
+  };
+
+  let hints = HopHints::run::<LabelOptions>(keyset, &[format!("--sels={sels}")], Vec::new());
+  Box::into_raw(Box::new(hints))
+}
+
+/// Reduce `hints` with `key`, as reported by Kakoune in `$kak_key`, such as `a`, `<backspace>` or `<esc>`.
+///
+/// `hints` is left untouched. Return null if an argument is null or not UTF-8, or if `hints` is not waiting for a key;
+/// the result must be freed with [`hop_hints_free`].
+///
+/// # Safety
+///
+/// `hints` must be null or returned by [`hop_generate_labels`] or [`hop_reduce`] and not freed yet; `key` must be null
+/// or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hop_reduce(hints: *const HopHints, key: *const c_char) -> *mut HopHints {
+  let (Some(hints), Some(key)) = (hints.as_ref(), str_arg(key)) else {
+    return ptr::null_mut();
+  };
+
+  if hints.status != HopStatus::Hints {
+    return ptr::null_mut();
+  }
+
+  let args = [
+    format!("--key={key}"),
+    format!("--typed={}", hints.typed.to_string_lossy()),
+    "--restore".to_owned(),
+  ];
+  let hints = HopHints::run::<ReduceOptions>(&hints.keyset, &args, hints.replace_ranges.clone());
+  Box::into_raw(Box::new(hints))
+}
+
+/// State of `hints`; a null `hints` is reported as failed.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_status(hints: *const HopHints) -> HopStatus {
+  hints.as_ref().map_or(HopStatus::Fail, |hints| hints.status)
+}
+
+/// Number of remaining hints, or of targets if the hop resolved.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_count(hints: *const HopHints) -> usize {
+  hints.as_ref().map_or(0, |hints| hints.sels.len())
+}
+
+/// Selection of the `i`-th remaining hint, or `i`-th target if the hop resolved, the first one being the main one.
+///
+/// Return null if `i` is out of bounds. The string is owned by `hints`.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_sel(hints: *const HopHints, i: usize) -> *const c_char {
+  hints
+    .as_ref()
+    .map_or(ptr::null(), |hints| nth_str(&hints.sels, i))
+}
+
+/// Full label of the `i`-th remaining hint, including the keys typed so far.
+///
+/// Return null if `i` is out of bounds or the hop is not waiting for a key. The string is owned by `hints`.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_label(hints: *const HopHints, i: usize) -> *const c_char {
+  hints
+    .as_ref()
+    .map_or(ptr::null(), |hints| nth_str(&hints.labels, i))
+}
+
+/// Keys typed so far. The string is owned by `hints`.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_typed(hints: *const HopHints) -> *const c_char {
+  hints
+    .as_ref()
+    .map_or(ptr::null(), |hints| hints.typed.as_ptr())
+}
+
+/// Why the hop cannot start, or an empty string if it did not fail. The string is owned by `hints`.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`].
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_message(hints: *const HopHints) -> *const c_char {
+  hints
+    .as_ref()
+    .map_or(ptr::null(), |hints| hints.message.as_ptr())
+}
+
+/// Free `hints`, and the strings it owns.
+///
+/// # Safety
+///
+/// `hints` must be null or a live pointer returned by [`hop_generate_labels`] or [`hop_reduce`]; it must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hop_hints_free(hints: *mut HopHints) {
+  if !hints.is_null() {
+    drop(Box::from_raw(hints));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ffi::{c_char, CStr, CString};
+
+  use crate::{
+    hop_generate_labels, hop_hints_count, hop_hints_free, hop_hints_label, hop_hints_message,
+    hop_hints_sel, hop_hints_status, hop_hints_typed, hop_reduce, HopHints, HopStatus,
+  };
+
+  /// Owned copy of a C string returned by the bindings.
+  fn string(s: *const c_char) -> String {
+    assert!(!s.is_null());
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+  }
+
+  /// Remaining selections and labels of `hints`.
+  fn hinted(hints: *const HopHints) -> Vec<(String, String)> {
+    let count = unsafe { hop_hints_count(hints) };
+    (0..count)
+      .map(|i| unsafe {
+        (
+          string(hop_hints_sel(hints, i)),
+          string(hop_hints_label(hints, i)),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn round_trip() {
+    let keyset = CString::new("ab").unwrap();
+    let sels = CString::new("1.1,1.1 2.1,2.1 3.1,3.1").unwrap();
+    let (b, a) = (CString::new("b").unwrap(), CString::new("a").unwrap());
+
+    unsafe {
+      let generated = hop_generate_labels(keyset.as_ptr(), sels.as_ptr());
+      assert_eq!(hop_hints_status(generated), HopStatus::Hints);
+      assert_eq!(
+        hinted(generated),
+        [
+          ("1.1,1.1".to_owned(), "a".to_owned()),
+          ("2.1,2.1".to_owned(), "ba".to_owned()),
+          ("3.1,3.1".to_owned(), "bb".to_owned()),
+        ]
+      );
+
+      let reduced = hop_reduce(generated, b.as_ptr());
+      assert_eq!(hop_hints_status(reduced), HopStatus::Hints);
+      assert_eq!(string(hop_hints_typed(reduced)), "b");
+      assert_eq!(
+        hinted(reduced),
+        [
+          ("2.1,2.1".to_owned(), "ba".to_owned()),
+          ("3.1,3.1".to_owned(), "bb".to_owned()),
+        ]
+      );
+
+      let jumped = hop_reduce(reduced, a.as_ptr());
+      assert_eq!(hop_hints_status(jumped), HopStatus::Jump);
+      assert_eq!(hop_hints_count(jumped), 1);
+      assert_eq!(string(hop_hints_sel(jumped, 0)), "2.1,2.1");
+
+      // resolved hops do not wait for keys anymore
+      assert!(hop_reduce(jumped, a.as_ptr()).is_null());
+
+      hop_hints_free(generated);
+      hop_hints_free(reduced);
+      hop_hints_free(jumped);
+    }
+  }
+
+  #[test]
+  fn invalid_sels() {
+    let keyset = CString::new("ab").unwrap();
+    let sels = CString::new("1.1,1.1 nope").unwrap();
+
+    unsafe {
+      let hints = hop_generate_labels(keyset.as_ptr(), sels.as_ptr());
+      assert_eq!(hop_hints_status(hints), HopStatus::Fail);
+      assert!(!string(hop_hints_message(hints)).is_empty());
+      hop_hints_free(hints);
+
+      assert!(hop_generate_labels(std::ptr::null(), sels.as_ptr()).is_null());
+      assert_eq!(hop_hints_status(std::ptr::null()), HopStatus::Fail);
+    }
+  }
+}
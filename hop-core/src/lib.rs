@@ -0,0 +1,7283 @@
+//! Label engine of hop-kak: generating labels for selections, reducing them as keys are typed, and rendering the
+//! result for Kakoune, Helix or any other tool.
+
+#[cfg(feature = "kakoune")]
+use std::fmt::Write as _;
+use std::{
+  borrow::Cow,
+  collections::{btree_map::Entry, BTreeMap, VecDeque},
+  convert::Infallible,
+  fmt::Display,
+  fs,
+  io::{self, Read, Write},
+  iter,
+  str::FromStr,
+};
+
+#[cfg(feature = "cli")]
+use clap::{Args, ValueEnum};
+use regex::Regex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Options shared by all hops, as passed on the command line.
+///
+/// The options users typically tune once and for all fall back to `HOP_KAK_*` environment variables.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
+pub struct Options {
+  /// Keyset to use as base for hints; defaults to the `qwerty-home` preset.
+  ///
+  /// Keys can be weighted by passing a space separated list of `key:weight` instead; e.g. `f:3 j:3 d:2 k:2 s a`. Keys
+  /// without weight have a weight of 1.
+  #[cfg_attr(feature = "cli", clap(short, long, env = "HOP_KAK_KEYSET"))]
+  pub keyset: Option<String>,
+
+  /// Pairs of keys which must never be typed one after the other in a label.
+  ///
+  /// This is a space separated list of bigrams; e.g. `fj jf`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_FORBID_BIGRAMS"))]
+  pub forbid_bigrams: Option<String>,
+
+  /// Keys typed by each hand, so that labels alternate hands as much as possible.
+  ///
+  /// This is a space separated list of groups of keys, one per hand; e.g. `qwertasdfgzxcvb yuiophjklnm`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_LAYOUT"))]
+  pub layout: Option<String>,
+
+  /// Built-in keyset for a keyboard layout, ordered by finger strength.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "keyset"))]
+  pub keyset_preset: Option<KeysetPreset>,
+
+  /// Append the uppercase variants of the lowercase keys to the keyset.
+  ///
+  /// Single-key labels are then available for up to twice more selections. Uppercase keys come last, so they are only
+  /// used once the lowercase ones are exhausted.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub uppercase: bool,
+
+  /// Selection handle to hint.
+  ///
+  /// It’s possible to either select the anchor or the cursor. Without it, the targets are selected the way they were
+  /// given, their anchor and cursor in the same order, and labels are displayed at their start.
+  #[cfg_attr(feature = "cli", clap(short, long, env = "HOP_KAK_HANDLE"))]
+  pub handle: Option<Handle>,
+
+  /// Side of the selections the labels are displayed on.
+  ///
+  /// Either the `start` or the `end` of the selections. Default to the side of `handle`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub label_at: Option<LabelAt>,
+
+  /// Where the cursor lands in the target, instead of selecting it whole.
+  ///
+  /// Either the `start`, the `end` or the `center` of the target.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub anchor: Option<Anchor>,
+
+  /// Selections the hop started from.
+  ///
+  /// Same syntax as `sels`, the first selection being the main one; you typically want to pass
+  /// `$kak_selections_desc` here. They are saved to the jump list before jumping.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub origin: Option<String>,
+
+  /// Extend the main selection of `origin` to the target instead of jumping to it.
+  ///
+  /// The anchor of the main selection is kept, and the cursor is moved to the target.
+  #[cfg_attr(feature = "cli", clap(long, requires = "origin"))]
+  pub extend: bool,
+
+  /// Add the target to the selections of `origin` instead of replacing them.
+  #[cfg_attr(feature = "cli", clap(long, requires = "origin"))]
+  pub add: bool,
+
+  /// Apply an operator to the text from the main selection of `origin` to the target, as Vim’s `d<motion>`.
+  ///
+  /// Either `yank`, `delete`, `change`, or the keys to execute on the extended selection; e.g. `<a-j>`. The selections
+  /// of `origin` are restored after yanking.
+  #[cfg_attr(feature = "cli", clap(long, requires = "origin", conflicts_with_all = ["extend", "add", "multi", "sticky"]))]
+  pub operator: Option<Operator>,
+
+  /// Pick mode.
+  ///
+  /// Read names on the standard input, one per line, list them in a scratch buffer and hint each of them; the chosen
+  /// one is then switched to, instead of jumped to. Either `buffer` or `client` names.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["origin", "multi", "sticky"]))]
+  pub pick: Option<Pick>,
+
+  /// Open the text of the chosen target instead of jumping to it.
+  ///
+  /// `edit` opens it as a file with Kakoune’s `edit`, at the line and column of `file:line:col` locations. Anything else
+  /// is a shell command run in the background with the text as its only argument; e.g. `xdg-open`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["multi", "sticky", "operator", "pick"]))]
+  pub open: Option<Open>,
+
+  /// Multi-target mode.
+  ///
+  /// Once a label is fully typed, its selection is picked and the remaining labels are displayed again, until `<ret>`
+  /// is pressed; all the picked selections are then selected.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub multi: bool,
+
+  /// Sticky mode.
+  ///
+  /// Once a label is fully typed, its selection is jumped to and the remaining labels are displayed again, so that
+  /// several targets can be visited in sequence, until the hop is cancelled.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "multi"))]
+  pub sticky: bool,
+
+  /// Do not jump automatically when there is a single target to hint.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub no_auto_jump: bool,
+
+  /// Keys cancelling the hop.
+  ///
+  /// This is a space separated list of keys, as reported by Kakoune; e.g. `<esc> <c-c> <c-g>`. Default to `<esc>`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_CANCEL_KEYS"))]
+  pub cancel_keys: Option<String>,
+
+  /// Type-to-narrow mode.
+  ///
+  /// Keys narrow the hints down to the targets which text continues with them, until a single one is left; `<tab>`
+  /// switches to typing labels among the remaining ones. Requires the buffer text on the standard input, as with
+  /// `--words`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "multi"))]
+  pub narrow: bool,
+
+  /// Let digits not matching any label pick the Nth remaining target, in document order.
+  ///
+  /// The target is picked as soon as no other digit can follow, or when `<ret>` is pressed; e.g. `3` picks the third
+  /// target if there are less than 30 of them.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "multi"))]
+  pub count: bool,
+
+  /// Cancel the hop when a key doesn’t match any label, instead of ignoring it.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub cancel_on_unknown: bool,
+
+  /// Show an info box listing the keys that still match a label, along with how many targets each of them keeps.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub info: bool,
+
+  /// Reduce the labels whatever the case of the keys; e.g. `A` reduces the labels starting with `a`.
+  ///
+  /// An uppercase key matching a label in its own case is always preferred, so that labels generated with `uppercase`
+  /// are still reachable.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub ignore_case: bool,
+
+  /// Kakoune commands to run once the hop resolved and the target is selected.
+  ///
+  /// In sticky mode, they are run every time a target is jumped to.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub then: Option<String>,
+
+  /// Prefix of the Kakoune options, faces and highlighters used to display the hints.
+  ///
+  /// Hints are stored in the `<prefix>_ranges` option, which must be declared, and displayed with the
+  /// `window/<prefix>-ranges` highlighter and the `<prefix>_label_head` and `<prefix>_label_tail` faces. Default to
+  /// `hop`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_PREFIX"))]
+  pub prefix: Option<String>,
+
+  /// How the targets of the hop were found, to repeat it once resolved.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long, allow_hyphen_values = true))]
+  pub repeat: Option<String>,
+
+  /// Timestamp of the buffer the selections were taken from; you typically want to pass `$kak_timestamp` here.
+  ///
+  /// The hints are only valid for that version of the buffer, so the hop is cancelled if the buffer changes before it
+  /// resolves.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub timestamp: Option<usize>,
+
+  /// Dim the text of the window while hinting, with the `<prefix>_dim` face.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub dim: bool,
+
+  /// How labels are displayed.
+  ///
+  /// `replace` displays the labels over the text of the selections. `ranges` keeps the text visible: it is highlighted
+  /// with the `<prefix>_target` face, from the `<prefix>_targets` option, and labels are inserted before it. Default to
+  /// `replace`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_HIGHLIGHTER"))]
+  pub highlighter: Option<Highlighter>,
+
+  /// How the labels stand out from the text.
+  ///
+  /// `block` displays them with the faces as declared. `underline`, `reverse` and `italic` add that attribute to the
+  /// label faces and to the `<prefix>_target` face, for themes and terminals in which the faces alone are hard to read.
+  /// Default to `block`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_STYLE"))]
+  pub style: Option<Style>,
+
+  /// Do not start the label of a selection with the character following it in the buffer text.
+  ///
+  /// That way, one can keep typing after a search without selecting a label by accident. Requires the buffer text on
+  /// the standard input, as with `--words`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub avoid_conflicts: bool,
+
+  /// Start the label of a selection with its first character, when it is in the keyset.
+  ///
+  /// Selections starting with the same character share it as first key. Requires the buffer text on the standard input,
+  /// as with `--words`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "avoid_conflicts"))]
+  pub semantic: bool,
+
+  /// Length of every label, even when shorter labels would do.
+  ///
+  /// The hop fails if there are more selections than labels of that length.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["avoid_conflicts", "semantic"]))]
+  pub label_len: Option<usize>,
+
+  /// Assign the labels according to the positions of the selections only.
+  ///
+  /// Hinting the same selections twice gives them the same labels, whatever their order or the position of the cursor;
+  /// the shortest labels don’t go to the closest selections anymore, though.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub stable: bool,
+
+  /// Order in which the selections are given labels, the shortest ones coming first.
+  ///
+  /// Either the `document` order, the `distance` to the cursor — the default when the cursor is known — or a `random`
+  /// one. Without cursor, `distance` falls back to the document order.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "stable"))]
+  pub order: Option<Order>,
+
+  /// Seed of the `random` order, so that it can be reproduced.
+  ///
+  /// hop-kak seeds it with the clock when it is not given.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub seed: Option<u64>,
+
+  /// Give the selections starting on the same line the same first keys, followed by distinct keys within the line.
+  ///
+  /// One picks the line first, then the spot on it.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["avoid_conflicts", "semantic", "label_len"]))]
+  pub group_by_line: bool,
+
+  /// Give fresh labels to the remaining selections after each key, instead of keeping the rest of their labels.
+  ///
+  /// Fewer selections need shorter labels, which saves keys on deep label trees. The buffer text is not known while
+  /// reducing, so the labels of `--semantic` and `--avoid-conflicts` cannot be given again.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["semantic", "avoid_conflicts"]))]
+  pub relabel: bool,
+
+  /// Maximum length of the labels.
+  ///
+  /// When there are more selections than labels of that length, `over_capacity` tells what to do.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_MAX_DEPTH"))]
+  pub max_depth: Option<usize>,
+
+  /// What to do when there are more selections than labels of `max_depth` keys.
+  ///
+  /// Either `fail` the hop, `drop` the farthest selections, or `page` them: the last key of the keyset is then reserved
+  /// to display the next page of selections. Default to `fail`.
+  #[cfg_attr(feature = "cli", clap(long, requires = "max_depth"))]
+  pub over_capacity: Option<OverCapacity>,
+
+  /// Maximum number of selections to hint.
+  ///
+  /// The farthest selections — or the last ones, according to `order` — are not hinted, and a message tells how many
+  /// were dropped.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub max_targets: Option<usize>,
+
+  /// Unit of the text covered by each character of a label.
+  ///
+  /// Kakoune columns are bytes, so labels drift on lines with multibyte characters unless `char` or `grapheme` is used.
+  /// Both require the buffer text on the standard input, as with `--words`. Default to `byte`.
+  #[cfg_attr(feature = "cli", clap(long, env = "HOP_KAK_COLUMN_UNIT"))]
+  pub column_unit: Option<ColumnUnit>,
+
+  /// Display labels in full past the selections narrower than them, instead of truncating them.
+  ///
+  /// Labels go past the end of the selection, or before its start when hinting the cursor.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub overflow: bool,
+}
+
+/// Options of `label`, starting a hop.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
+#[cfg_attr(feature = "cli", clap(group(
+  clap::ArgGroup::new("targets")
+    .args([
+      "sels",
+      "sels0",
+      "binary",
+      "words",
+      "pattern",
+      "todos",
+      "lines",
+      "marks",
+      "symbols",
+      "grep",
+      "links",
+      "brackets",
+      "paragraphs",
+      "sentences",
+      "blank_lines",
+      "indents",
+      "numbers",
+      "find",
+      "vertical",
+      "search2",
+      "pick",
+    ])
+)))]
+pub struct LabelOptions {
+  /// Selections to hint.
+  ///
+  /// The syntax of a single selection is two pairs separated by a comma, each pair being a pair of period separated
+  /// number: `line_start.column_start,line_end.column_end`. Selections in another buffer than the current one are
+  /// prefixed with its name and `@`; e.g. `src/main.rs@12.1,12.4`.
+  ///
+  /// Selections are space separated. If set to `-`, selections are read from the standard input.
+  #[cfg_attr(feature = "cli", clap(short, long))]
+  pub sels: Option<String>,
+
+  /// Read the selections to hint from the standard input, NUL separated, instead of `sels`.
+  ///
+  /// Entries are taken as they are, so that buffer names can contain spaces.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub sels0: bool,
+
+  /// Read the selections to hint from the standard input, in a length-prefixed binary encoding, instead of `sels`.
+  ///
+  /// Integers are little endian: a `u32` count, then for each selection the line and column of its anchor and of its
+  /// cursor as `u32`, and its buffer as a `u16` length followed by its UTF-8 name, empty for the current buffer.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub binary: bool,
+
+  /// Word mode.
+  ///
+  /// Read the buffer text on the standard input and hint the start of every word in it, instead of using `sels`.
+  #[cfg_attr(feature = "cli", clap(short, long))]
+  pub words: bool,
+
+  /// Split the words at case changes and underscores in word mode, so that `fooBar_baz` gives three targets.
+  #[cfg_attr(feature = "cli", clap(long, requires = "words"))]
+  pub subwords: bool,
+
+  /// Line offset of the text read on the standard input.
+  ///
+  /// This is the line number of the first line of the text; typically, the first visible line of the window.
+  #[cfg_attr(feature = "cli", clap(long, default_value_t = 1))]
+  pub line_offset: usize,
+
+  /// Two-character search mode.
+  ///
+  /// Wait for the user to type two characters, and hint every occurrence of them in the buffer text read on the
+  /// standard input.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub search2: bool,
+
+  /// Characters already typed in two-character search mode.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub search_keys: Option<String>,
+
+  /// Pattern mode.
+  ///
+  /// Hint every match of the regular expression in the buffer text read on the standard input, instead of using
+  /// `sels`.
+  #[cfg_attr(feature = "cli", clap(short, long, value_parser = Regex::new))]
+  pub pattern: Option<Regex>,
+
+  /// TODO mode.
+  ///
+  /// Hint every `TODO`, `FIXME`, `HACK` and `XXX` marker in the buffer text read on the standard input, instead of using
+  /// `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub todos: bool,
+
+  /// Line-start mode.
+  ///
+  /// Hint the first column of every line in the range, instead of using `sels`.
+  ///
+  /// The syntax is `first_line,last_line`, both included.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub lines: Option<LineRange>,
+
+  /// Mark mode.
+  ///
+  /// Read registers on the standard input, one per line, as the name of the register followed by its contents — marks
+  /// saved with `Z` being `buffile@timestamp@main` and the selections — and hint the main selection of every mark,
+  /// instead of using `sels`. Registers not holding marks are ignored.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub marks: bool,
+
+  /// Buffer of the marks to hint, in mark mode; you typically want to pass `$kak_buffile` here.
+  ///
+  /// Marks saved in other buffers are ignored.
+  #[cfg_attr(feature = "cli", clap(long, requires = "marks"))]
+  pub buffile: Option<String>,
+
+  /// Symbol mode.
+  ///
+  /// Read symbols from this file, one per line as `line.column<TAB>name` — e.g. generated from ctags or a language
+  /// server — and hint every symbol, instead of using `sels`. Pass `-` to read them on the standard input. Lines not
+  /// holding a symbol are ignored.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub symbols: Option<String>,
+
+  /// Only hint the symbols which name starts with this prefix, in symbol mode.
+  #[cfg_attr(feature = "cli", clap(long, requires = "symbols"))]
+  pub symbol_prefix: Option<String>,
+
+  /// Grep mode.
+  ///
+  /// Read the text of a grep buffer on the standard input and hint every `file:line:col` location starting a line — the
+  /// column being optional — instead of using `sels`. The chosen location is opened, as with `--open edit`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub grep: bool,
+
+  /// Link mode.
+  ///
+  /// Read the buffer text on the standard input and hint every URL and file path in it, instead of using `sels`. The
+  /// chosen one is jumped to, unless `--open` is passed.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub links: bool,
+
+  /// Bracket mode.
+  ///
+  /// Read the buffer text on the standard input and hint every character of the given set in it, instead of using
+  /// `sels`. Default to `()[]{}<>`.
+  #[cfg_attr(feature = "cli", clap(long, num_args = 0..=1, default_missing_value = "()[]{}<>"))]
+  pub brackets: Option<String>,
+
+  /// Paragraph mode.
+  ///
+  /// Read the buffer text on the standard input and hint the start of the first line of every paragraph in it — blocks
+  /// of lines separated by blank lines — instead of using `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub paragraphs: bool,
+
+  /// Sentence mode.
+  ///
+  /// Read the buffer text on the standard input and hint the first character of every sentence in it, instead of using
+  /// `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub sentences: bool,
+
+  /// Blank-line mode.
+  ///
+  /// Read the buffer text on the standard input and hint every line made of whitespace only in it, instead of using
+  /// `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub blank_lines: bool,
+
+  /// Indentation mode.
+  ///
+  /// Read the buffer text on the standard input and hint the first non-whitespace character of every line which
+  /// indentation differs from the one of the line before or after it, instead of using `sels`. Blank lines are skipped.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub indents: bool,
+
+  /// Number mode.
+  ///
+  /// Read the buffer text on the standard input and hint every number literal in it, instead of using `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub numbers: bool,
+
+  /// Find mode, as Vim’s `f`.
+  ///
+  /// Read the buffer text on the standard input — typically the current line or the visible lines — and hint every
+  /// occurrence of the character typed with this key in it, instead of using `sels`. The key is reported by Kakoune in
+  /// `$kak_key`; e.g. `<space>`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub find: Option<String>,
+
+  /// Land on the character `before` or `after` every occurrence in find mode, as Vim’s `t` and `T`.
+  #[cfg_attr(feature = "cli", clap(long, requires = "find"))]
+  pub till: Option<Till>,
+
+  /// Vertical mode.
+  ///
+  /// Read the visible buffer text on the standard input and hint the character at the display column of `cursor` on
+  /// every other line, instead of using `sels`. Lines too short to reach that column are not hinted.
+  #[cfg_attr(feature = "cli", clap(long, requires = "cursor"))]
+  pub vertical: bool,
+
+  /// Display width of a tab, in vertical mode. You typically want to pass `$kak_opt_tabstop` here.
+  #[cfg_attr(feature = "cli", clap(long, default_value_t = 8))]
+  pub tabstop: usize,
+
+  /// Land just `inside` or `outside` of the brackets, in bracket mode, instead of on them.
+  #[cfg_attr(feature = "cli", clap(long, requires = "brackets"))]
+  pub land: Option<Land>,
+
+  /// Visible lines of the window.
+  ///
+  /// Selections starting outside of this range are not hinted. The syntax is `first_line,last_line`, both included.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub view_range: Option<LineRange>,
+
+  /// Do not hint the selections overlapping the selections of `origin`, where the cursor already is.
+  #[cfg_attr(feature = "cli", clap(long, requires = "origin"))]
+  pub exclude_current: bool,
+
+  /// Position of the main cursor.
+  ///
+  /// When set, the shortest labels are assigned to the selections closest to it. The syntax is `line.column`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub cursor: Option<Pos>,
+
+  /// Key typed in two-character search mode.
+  #[cfg_attr(feature = "cli", clap(short = 'z', long, requires = "search2"))]
+  pub key: Option<String>,
+}
+
+impl LabelOptions {
+  /// Whether the targets to hint are given, with `sels` or one of the options finding them; `--pick`, which is one of
+  /// the shared options, is not looked at.
+  pub fn has_targets(&self) -> bool {
+    self.sels.is_some()
+      || self.sels0
+      || self.binary
+      || self.words
+      || self.pattern.is_some()
+      || self.todos
+      || self.lines.is_some()
+      || self.marks
+      || self.symbols.is_some()
+      || self.grep
+      || self.links
+      || self.brackets.is_some()
+      || self.paragraphs
+      || self.sentences
+      || self.blank_lines
+      || self.indents
+      || self.numbers
+      || self.find.is_some()
+      || self.vertical
+      || self.search2
+  }
+}
+
+/// Options of `reduce`, reducing the labels of a hop with a key.
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
+pub struct ReduceOptions {
+  /// Reduction key.
+  ///
+  /// Key used to reduce the list of `labels`, as reported by Kakoune in `$kak_key`.
+  #[cfg_attr(
+    feature = "cli",
+    clap(short = 'z', long, required_unless_present = "again")
+  )]
+  pub key: Option<String>,
+
+  /// Display the hints of `sels` and `labels` again instead of reducing them with a key; used by `hop-again`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "key"))]
+  pub again: bool,
+
+  /// Hinted selections.
+  ///
+  /// Same syntax as in `label`. If set to `-`, selections are read from the standard input.
+  #[cfg_attr(feature = "cli", clap(short, long, required_unless_present_any = ["restore", "sels0", "binary"]))]
+  pub sels: Option<String>,
+
+  /// Read the hinted selections from the standard input, NUL separated, instead of `sels`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "sels"))]
+  pub sels0: bool,
+
+  /// Labels hints to reduce.
+  ///
+  /// This is a list of labels, space separated string, to reduce. Those are zipped with `sels`. If set to `-`, labels
+  /// are read from the standard input; if both `sels` and `labels` are read from the standard input, the first line
+  /// contains the selections and the second line contains the labels.
+  #[cfg_attr(feature = "cli", clap(short, long, required_unless_present_any = ["restore", "labels0", "binary"]))]
+  pub labels: Option<String>,
+
+  /// Read the labels from the standard input, NUL separated, instead of `labels`.
+  ///
+  /// With `sels0` too, each selection is followed by its label.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with = "labels"))]
+  pub labels0: bool,
+
+  /// Read the hinted selections and their labels from the standard input, in the binary encoding of `label`.
+  ///
+  /// The selections are followed by a `u32` count of labels, then each label as a `u16` length followed by its UTF-8
+  /// bytes.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["sels", "labels", "sels0", "labels0"]))]
+  pub binary: bool,
+
+  /// Reduce the labels kept by the daemon from the previous request, instead of `sels` and `labels`.
+  #[cfg_attr(feature = "cli", clap(long, conflicts_with_all = ["sels", "labels", "sels0", "labels0", "binary"]))]
+  pub restore: bool,
+
+  /// Keys typed so far to reduce `labels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub typed: Option<String>,
+
+  /// Selections picked so far in multi-target mode.
+  ///
+  /// Same syntax as `sels`.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub picked: Option<String>,
+
+  /// Columns the labels are displayed on.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub columns: Option<String>,
+
+  /// Display widths of the text the labels are displayed on.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub widths: Option<String>,
+
+  /// Count typed so far to pick a target.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub typed_count: Option<usize>,
+
+  /// Characters typed so far in type-to-narrow mode; labels are typed if absent.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub narrowed: Option<String>,
+
+  /// Text of the targets in type-to-narrow mode.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub texts: Option<String>,
+
+  /// Timestamp of the buffer when the key was typed, compared to `timestamp`.
+  ///
+  /// You should never need to use that argument.
+  #[cfg_attr(feature = "cli", clap(long))]
+  pub buffer_timestamp: Option<usize>,
+}
+
+/// What a hop is asked to do.
+#[derive(Debug)]
+pub enum Action {
+  /// Start a hop, hinting the selections.
+  Label(LabelOptions),
+
+  /// Reduce the labels of a hop with a key.
+  Reduce(ReduceOptions),
+}
+
+impl From<LabelOptions> for Action {
+  fn from(options: LabelOptions) -> Self {
+    Self::Label(options)
+  }
+}
+
+impl From<ReduceOptions> for Action {
+  fn from(options: ReduceOptions) -> Self {
+    Self::Reduce(options)
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Format {
+  #[cfg(feature = "kakoune")]
+  #[default]
+  Kakoune,
+
+  #[cfg_attr(not(feature = "kakoune"), default)]
+  Json,
+
+  Helix,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum KeysetPreset {
+  QwertyHome,
+  Colemak,
+  Dvorak,
+  Azerty,
+  Workman,
+}
+
+impl KeysetPreset {
+  /// Keys of the layout; home row first, from the index to the pinky fingers, alternating hands, then the top and
+  /// bottom rows.
+  pub fn keys(self) -> &'static str {
+    match self {
+      KeysetPreset::QwertyHome => "fjdkslaghrueiwoqptyvmcnxbz",
+      KeysetPreset::Colemak => "tnseriaodhplfuwygjcmvkbqxz",
+      KeysetPreset::Dvorak => "uhetonasidpgycfrlkmjwbqvxz",
+      KeysetPreset::Azerty => "fjdkslqmghrueizoaptyvncxwb",
+      KeysetPreset::Workman => "tnhesoaigywfrudpbjqclmxvkz",
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum OverCapacity {
+  #[default]
+  Fail,
+
+  Drop,
+
+  Page,
+}
+
+impl Display for OverCapacity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      OverCapacity::Fail => f.write_str("fail"),
+      OverCapacity::Drop => f.write_str("drop"),
+      OverCapacity::Page => f.write_str("page"),
+    }
+  }
+}
+
+/// Order in which selections are given labels.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Order {
+  Document,
+  Distance,
+  Random,
+}
+
+impl Display for Order {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Order::Document => f.write_str("document"),
+      Order::Distance => f.write_str("distance"),
+      Order::Random => f.write_str("random"),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ColumnUnit {
+  #[default]
+  Byte,
+
+  Char,
+
+  Grapheme,
+}
+
+impl Display for ColumnUnit {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ColumnUnit::Byte => f.write_str("byte"),
+      ColumnUnit::Char => f.write_str("char"),
+      ColumnUnit::Grapheme => f.write_str("grapheme"),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Highlighter {
+  /// Labels replace the text of the selections, with the `replace-ranges` highlighter.
+  #[default]
+  Replace,
+
+  /// The text of the selections is highlighted with the `ranges` highlighter, and labels are inserted before it.
+  Ranges,
+}
+
+impl Display for Highlighter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Highlighter::Replace => f.write_str("replace"),
+      Highlighter::Ranges => f.write_str("ranges"),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Style {
+  /// Labels are displayed with the faces as declared.
+  #[default]
+  Block,
+
+  /// The faces are underlined.
+  Underline,
+
+  /// The faces are displayed in reverse video.
+  Reverse,
+
+  /// The faces are displayed in italic.
+  Italic,
+}
+
+impl Style {
+  /// Kakoune face spec displaying `face` with this style.
+  pub fn face(self, face: &str) -> String {
+    match self {
+      Style::Block => face.to_owned(),
+      Style::Underline => format!("+u@{face}"),
+      Style::Reverse => format!("+r@{face}"),
+      Style::Italic => format!("+i@{face}"),
+    }
+  }
+}
+
+impl Display for Style {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Style::Block => f.write_str("block"),
+      Style::Underline => f.write_str("underline"),
+      Style::Reverse => f.write_str("reverse"),
+      Style::Italic => f.write_str("italic"),
+    }
+  }
+}
+
+/// Side of the selections the labels are displayed on.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum LabelAt {
+  Start,
+  End,
+}
+
+impl Display for LabelAt {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LabelAt::Start => f.write_str("start"),
+      LabelAt::End => f.write_str("end"),
+    }
+  }
+}
+
+/// Side of a bracket to land on, in bracket mode.
+///
+/// The inside of an opening bracket is the character after it, and the inside of a closing one the character before it.
+/// Characters which are not brackets are landed on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Land {
+  Inside,
+  Outside,
+}
+
+/// Byte offset, display column and display width of every character of `line`.
+///
+/// Tabs extend to the next multiple of `tabstop`, and double-width characters, such as CJK ones, have a width of `2`.
+fn display_spans(line: &str, tabstop: usize) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+  let tabstop = tabstop.max(1);
+
+  line.char_indices().scan(0, move |col, (byte, c)| {
+    let width = match c {
+      '\t' => tabstop - *col % tabstop,
+      _ => c.width().unwrap_or(0),
+    };
+    let start = *col;
+    *col += width;
+    Some((byte, start, width))
+  })
+}
+
+/// Side of an occurrence to land on, in find mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Till {
+  Before,
+  After,
+}
+
+impl Display for Till {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Till::Before => f.write_str("before"),
+      Till::After => f.write_str("after"),
+    }
+  }
+}
+
+/// Where the cursor lands in a target.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Anchor {
+  Start,
+  End,
+
+  /// Middle column of a single-line target, or start of the middle line of a multi-line one.
+  Center,
+}
+
+impl Display for Anchor {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Anchor::Start => f.write_str("start"),
+      Anchor::End => f.write_str("end"),
+      Anchor::Center => f.write_str("center"),
+    }
+  }
+}
+
+/// What the names listed in pick mode are.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Pick {
+  /// Buffers, as listed in `%val{buflist}`.
+  Buffer,
+
+  /// Clients, as listed in `%val{client_list}`.
+  Client,
+}
+
+impl Pick {
+  /// Kakoune command switching to a name.
+  pub fn command(&self) -> &'static str {
+    match self {
+      Pick::Buffer => "buffer",
+      Pick::Client => "focus",
+    }
+  }
+}
+
+impl Display for Pick {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Pick::Buffer => f.write_str("buffer"),
+      Pick::Client => f.write_str("client"),
+    }
+  }
+}
+
+/// How the text of the chosen target is opened.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Open {
+  /// Edit the file it names, at its position if it is a [`Location`].
+  Edit,
+
+  /// Shell command run in the background with the text as its only argument; e.g. `xdg-open`.
+  Command(String),
+}
+
+impl FromStr for Open {
+  type Err = Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "edit" => Open::Edit,
+      command => Open::Command(command.to_owned()),
+    })
+  }
+}
+
+impl Display for Open {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Open::Edit => f.write_str("edit"),
+      Open::Command(command) => f.write_str(command),
+    }
+  }
+}
+
+/// Operator applied from the origin to the target of a hop.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Operator {
+  Yank,
+  Delete,
+  Change,
+
+  /// Keys to execute, as passed to `execute-keys`.
+  Keys(String),
+}
+
+impl Operator {
+  /// Keys applying the operator to the selections.
+  pub fn keys(&self) -> &str {
+    match self {
+      Operator::Yank => "y",
+      Operator::Delete => "d",
+      Operator::Change => "c",
+      Operator::Keys(keys) => keys,
+    }
+  }
+}
+
+impl FromStr for Operator {
+  type Err = Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "yank" => Operator::Yank,
+      "delete" => Operator::Delete,
+      "change" => Operator::Change,
+      keys => Operator::Keys(keys.to_owned()),
+    })
+  }
+}
+
+impl Display for Operator {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Operator::Yank => f.write_str("yank"),
+      Operator::Delete => f.write_str("delete"),
+      Operator::Change => f.write_str("change"),
+      Operator::Keys(keys) => f.write_str(keys),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Handle {
+  #[default]
+  Anchor,
+
+  Cursor,
+}
+
+impl Display for Handle {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Handle::Anchor => f.write_str("anchor"),
+      Handle::Cursor => f.write_str("cursor"),
+    }
+  }
+}
+
+/// Keys labels are made of, ordered by importance, and the rules on their sequences.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Keyset {
+  keys: Vec<char>,
+
+  /// Weight of each key; missing weights are 1.
+  weights: Vec<usize>,
+
+  /// Pairs of keys which must not be typed one after the other.
+  forbidden_bigrams: Vec<[char; 2]>,
+
+  /// Keys typed by each hand.
+  hands: Vec<Vec<char>>,
+}
+
+impl Keyset {
+  pub fn new(keys: impl IntoIterator<Item = char>) -> Self {
+    Self {
+      keys: keys.into_iter().collect(),
+      ..Self::default()
+    }
+  }
+
+  /// Parse a keyset, either as a plain list of keys, or as a space separated list of `key:weight`.
+  ///
+  /// Invalid weights are parsed as 0, which is rejected by [`Keyset::validate_weights`].
+  pub fn parse(keyset: &str) -> Self {
+    if !keyset.contains(char::is_whitespace) {
+      return Self::new(keyset.chars());
+    }
+
+    let (keys, weights) = keyset
+      .split_whitespace()
+      .filter_map(|token| {
+        let mut chars = token.chars();
+        let key = chars.next()?;
+        let weight = match chars.as_str().strip_prefix(':') {
+          Some(weight) => weight.parse().unwrap_or(0),
+          None if chars.as_str().is_empty() => 1,
+          None => 0,
+        };
+        Some((key, weight))
+      })
+      .unzip();
+
+    Self {
+      keys,
+      weights,
+      ..Self::default()
+    }
+  }
+
+  /// Parse space separated bigrams, ignoring the ones not made of exactly two keys.
+  pub fn with_forbidden_bigrams(mut self, bigrams: &str) -> Self {
+    self.forbidden_bigrams = bigrams
+      .split_whitespace()
+      .filter_map(|bigram| {
+        let mut keys = bigram.chars();
+        match (keys.next(), keys.next(), keys.next()) {
+          (Some(first), Some(second), None) => Some([first, second]),
+          _ => None,
+        }
+      })
+      .collect();
+    self
+  }
+
+  /// Parse space separated groups of keys, one per hand.
+  pub fn with_layout(mut self, layout: &str) -> Self {
+    self.hands = layout
+      .split_whitespace()
+      .map(|hand| hand.chars().collect())
+      .collect();
+    self
+  }
+
+  /// Same rules, with other keys.
+  fn with_keys(&self, keys: Vec<char>) -> Self {
+    Self {
+      weights: keys.iter().map(|key| self.weight(*key)).collect(),
+      keys,
+      forbidden_bigrams: self.forbidden_bigrams.clone(),
+      hands: self.hands.clone(),
+    }
+  }
+
+  /// Weight of `key`; the heavier a key, the more it is used in labels.
+  fn weight(&self, key: char) -> usize {
+    self
+      .keys
+      .iter()
+      .position(|k| *k == key)
+      .and_then(|i| self.weights.get(i))
+      .copied()
+      .unwrap_or(1)
+  }
+
+  fn is_weighted(&self) -> bool {
+    self.weights.iter().any(|weight| *weight != 1)
+  }
+
+  /// Keyset of `options`: `--keyset` or `--keyset-preset`, with `--uppercase`, `--forbid-bigrams` and `--layout`.
+  pub fn from_options(options: &Options) -> Self {
+    let mut keyset = Keyset::parse(
+      options
+        .keyset
+        .as_deref()
+        .or(options.keyset_preset.map(KeysetPreset::keys))
+        .unwrap_or(KeysetPreset::QwertyHome.keys()),
+    );
+    if options.uppercase {
+      // uppercase keys are appended, so they get the default weight
+      keyset.keys = with_uppercase(keyset.keys);
+    }
+    keyset
+      .with_forbidden_bigrams(options.forbid_bigrams.as_deref().unwrap_or_default())
+      .with_layout(options.layout.as_deref().unwrap_or_default())
+  }
+
+  /// Check that labels can be generated with this keyset, describing what is wrong otherwise.
+  pub fn validate(&self) -> Result<(), String> {
+    validate_keyset(&self.keys).and_then(|_| self.validate_weights())
+  }
+
+  /// Check that every weight is a positive integer.
+  pub fn validate_weights(&self) -> Result<(), String> {
+    match self.keys.iter().find(|key| self.weight(**key) == 0) {
+      Some(key) => Err(format!(
+        "keyset key '{key}' has an invalid weight; weights must be positive integers"
+      )),
+      None => Ok(()),
+    }
+  }
+
+  /// Keys which can follow `key` in a label, or start a label if `None`.
+  ///
+  /// Keys of the other hand come first, so that labels alternate hands.
+  fn after(&self, key: Option<char>) -> Vec<char> {
+    let mut keys: Vec<_> = self
+      .keys
+      .iter()
+      .copied()
+      .filter(|next| self.follows(key, *next))
+      .collect();
+
+    if let (Some(key), false) = (key, self.hands.is_empty()) {
+      keys.sort_by_key(|next| self.same_hand(key, *next));
+    }
+
+    keys
+  }
+
+  /// Number of keys which can follow `key` in a label; same as `self.after(Some(key)).len()`, without allocating.
+  fn after_count(&self, key: char) -> usize {
+    self
+      .keys
+      .iter()
+      .filter(|next| self.follows(Some(key), **next))
+      .count()
+  }
+
+  /// Whether `next` can follow `key` in a label, or start a label if `None`.
+  fn follows(&self, key: Option<char>, next: char) -> bool {
+    !matches!(key, Some(key) if self.forbidden_bigrams.contains(&[key, next]))
+  }
+
+  /// Whether `a` and `b` are typed by the same hand, according to the layout.
+  fn same_hand(&self, a: char, b: char) -> bool {
+    self
+      .hands
+      .iter()
+      .any(|hand| hand.contains(&a) && hand.contains(&b))
+  }
+
+  /// Number of keys in `label` typed by the same hand as the previous key.
+  fn same_hand_count(&self, label: &[char]) -> usize {
+    label
+      .windows(2)
+      .filter(|bigram| self.same_hand(bigram[0], bigram[1]))
+      .count()
+  }
+
+  /// Whether `label` has no forbidden bigram.
+  fn allows(&self, label: &[char]) -> bool {
+    !label.windows(2).any(|bigram| {
+      self
+        .forbidden_bigrams
+        .iter()
+        .any(|forbidden| forbidden == bigram)
+    })
+  }
+
+  /// Arguments passing this keyset back to us.
+  pub fn args(&self) -> String {
+    let keys: String = if self.is_weighted() {
+      let keys: Vec<_> = self
+        .keys
+        .iter()
+        .map(|key| format!("{key}:{}", self.weight(*key)))
+        .collect();
+      keys.join(" ")
+    } else {
+      self.keys.iter().collect()
+    };
+    let mut args = format!("--keyset {}", sh_quote(&keys));
+
+    if !self.forbidden_bigrams.is_empty() {
+      let bigrams: Vec<String> = self
+        .forbidden_bigrams
+        .iter()
+        .map(|bigram| bigram.iter().collect())
+        .collect();
+      args.push_str(&format!(
+        " --forbid-bigrams {}",
+        sh_quote(&bigrams.join(" "))
+      ));
+    }
+
+    if !self.hands.is_empty() {
+      let hands: Vec<String> = self
+        .hands
+        .iter()
+        .map(|hand| hand.iter().collect())
+        .collect();
+      args.push_str(&format!(" --layout {}", sh_quote(&hands.join(" "))));
+    }
+
+    args
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trie {
+  key: char,
+  below: Vec<Trie>,
+
+  /// Paths to the saturated nodes, breadth-first, with their children to turn into nodes, the next one last. The next
+  /// child of the front node is being filled. Only used by the root.
+  queue: VecDeque<(Vec<usize>, Vec<usize>)>,
+
+  /// Keys of the children of the node being filled.
+  keys: Vec<char>,
+}
+
+impl Default for Trie {
+  fn default() -> Self {
+    Self {
+      key: ' ', // root is ignored
+      below: Vec::default(),
+      queue: VecDeque::default(),
+      keys: Vec::default(),
+    }
+  }
+}
+
+impl Trie {
+  fn new(key: char) -> Self {
+    Self {
+      key,
+      ..Self::default()
+    }
+  }
+
+  /// Grow the trie by one key.
+  ///
+  /// Return the length of the inserted label, or `None` if no key can be inserted, because of forbidden bigrams.
+  pub fn grow(&mut self, keyset: &Keyset) -> Option<usize> {
+    self.grow_after(keyset, None)
+  }
+
+  /// Grow the trie by one key, the root of the trie being after `key` in labels.
+  ///
+  /// Nodes are filled breadth-first, so that labels stay as short as possible, and growing takes constant time, but
+  /// for the depth of the trie.
+  pub fn grow_after(&mut self, keyset: &Keyset, key: Option<char>) -> Option<usize> {
+    if self.below.is_empty() && self.queue.is_empty() {
+      self.keys = keyset.after(key);
+    }
+
+    // the root is filled first
+    if self.queue.is_empty() {
+      if self.below.len() >= self.keys.len() {
+        return None;
+      }
+
+      self.below.push(Self::new(self.keys[self.below.len()]));
+
+      if self.below.len() == self.keys.len() {
+        self.saturate(keyset, Vec::new());
+      }
+
+      return Some(1);
+    }
+
+    let (path, leaves) = self.queue.front_mut()?;
+    let i = *leaves.last()?;
+    let len = path.len() + 2;
+    let below = &mut Self::below_mut(&mut self.below, path)[i].below;
+
+    // a leaf turned into a node loses its label, so it needs two children
+    if below.is_empty() {
+      below.reserve_exact(self.keys.len());
+      below.push(Self::new(self.keys[0]));
+    }
+
+    below.push(Self::new(self.keys[below.len()]));
+
+    if below.len() == self.keys.len() {
+      leaves.pop();
+
+      let mut leaf = path.clone();
+      leaf.push(i);
+
+      if leaves.is_empty() {
+        self.queue.pop_front();
+      }
+
+      self.saturate(keyset, leaf);
+    }
+
+    Some(len)
+  }
+
+  /// Queue the children of the saturated node at `path` that can become nodes — they need two keys after them — and get
+  /// ready to fill the next one.
+  ///
+  /// The heaviest child is filled first, then the last one.
+  fn saturate(&mut self, keyset: &Keyset, path: Vec<usize>) {
+    let below = Self::below_mut(&mut self.below, &path);
+    let mut leaves: Vec<_> = (0..below.len())
+      .filter(|&i| keyset.after_count(below[i].key) >= 2)
+      .collect();
+
+    if keyset.is_weighted() {
+      leaves.sort_by_key(|&i| (keyset.weight(below[i].key), i));
+    }
+
+    if !leaves.is_empty() {
+      self.queue.push_back((path, leaves));
+    }
+
+    self.keys = match self.queue.front() {
+      Some((path, leaves)) => {
+        let key = Self::below_mut(&mut self.below, path)[leaves[leaves.len() - 1]].key;
+        keyset.after(Some(key))
+      }
+
+      None => Vec::new(),
+    };
+  }
+
+  /// Children of the node at `path`, starting from the children of the root.
+  fn below_mut<'a>(mut below: &'a mut Vec<Trie>, path: &[usize]) -> &'a mut Vec<Trie> {
+    for &i in path {
+      below = &mut below[i].below;
+    }
+
+    below
+  }
+
+  /// Grow the trie repeatedly `n` times.
+  pub fn grow_repeatedly(&mut self, n: usize, keyset: &Keyset) {
+    for _ in 0..n {
+      self.grow(keyset);
+    }
+  }
+
+  /// Length of the longest label.
+  pub fn depth(&self) -> usize {
+    self
+      .below
+      .iter()
+      .map(|below| below.depth() + 1)
+      .max()
+      .unwrap_or_default()
+  }
+
+  /// Number of labels, up to `max`, that can be grown before one of them gets longer than `depth` keys.
+  pub fn capacity(keyset: &Keyset, depth: usize, max: usize) -> usize {
+    let mut trie = Self::default();
+
+    for n in 0..max {
+      // the inserted label is always among the longest ones
+      if !matches!(trie.grow(keyset), Some(len) if len <= depth) {
+        return n;
+      }
+    }
+
+    max
+  }
+
+  /// The first `count` labels of exactly `len` keys, in keyset order.
+  ///
+  /// There are fewer labels if there are not enough of them without forbidden bigrams.
+  pub fn fixed_labels(keyset: &Keyset, len: usize, count: usize) -> Vec<String> {
+    let keys = &keyset.keys;
+
+    let mut labels: Vec<_> = (0..label_capacity(keys, len))
+      .map(|mut i| {
+        let mut label = vec![keys[0]; len];
+
+        for key in label.iter_mut().rev() {
+          *key = keys[i % keys.len()];
+          i /= keys.len();
+        }
+
+        label
+      })
+      .filter(|label| keyset.allows(label))
+      .collect();
+
+    // labels alternating hands first
+    labels.sort_by_key(|label| keyset.same_hand_count(label));
+
+    labels
+      .into_iter()
+      .take(count)
+      .map(|label| label.into_iter().collect())
+      .collect()
+  }
+
+  /// Labels of targets which must not start with their forbidden keys, in the order of `forbidden`.
+  ///
+  /// The trie is grown until every target gets a label; the shortest labels go to the first targets.
+  pub fn labels_avoiding(keyset: &Keyset, forbidden: &[Vec<char>]) -> Vec<String> {
+    let mut trie = Self::default();
+    trie.grow_repeatedly(forbidden.len(), keyset);
+
+    loop {
+      let mut labels = trie.labels();
+      let assigned: Option<Vec<_>> = forbidden
+        .iter()
+        .map(|keys| {
+          let i = labels
+            .iter()
+            .position(|label| !label.starts_with(&keys[..]))?;
+          Some(labels.remove(i))
+        })
+        .collect();
+
+      match assigned {
+        Some(assigned) => return assigned,
+
+        // conflicts cannot be avoided with the keys left
+        None if trie.grow(keyset).is_none() => return trie.labels(),
+
+        None => (),
+      }
+    }
+  }
+
+  /// Labels of targets starting with their first character, in the order of `firsts`.
+  ///
+  /// Targets sharing the same first character get the labels of a sub-trie after it; targets which first character is
+  /// not in `keyset` get generic labels from the other keys. `None` if there are not enough other keys for them.
+  pub fn semantic_labels(keyset: &Keyset, firsts: &[Option<char>]) -> Option<Vec<String>> {
+    // the first key of every target, if in the keyset; lowercase keys are used for uppercase characters
+    let firsts: Vec<_> = firsts
+      .iter()
+      .map(|first| {
+        let first = (*first)?;
+        let lowercase = first.to_lowercase().next()?;
+        [first, lowercase]
+          .into_iter()
+          .find(|key| keyset.keys.contains(key))
+      })
+      .collect();
+
+    let rest_keyset = keyset.with_keys(
+      keyset
+        .keys
+        .iter()
+        .copied()
+        .filter(|key| !firsts.contains(&Some(*key)))
+        .collect(),
+    );
+    let rest_count = firsts.iter().filter(|first| first.is_none()).count();
+
+    let mut rest_labels = match rest_count {
+      0 => Vec::new(),
+      1 => vec![rest_keyset.keys.first()?.to_string()],
+      _ if rest_keyset.keys.len() < 2 => return None,
+      _ => {
+        let mut trie = Self::default();
+        trie.grow_repeatedly(rest_count, &rest_keyset);
+        trie.labels()
+      }
+    }
+    .into_iter();
+
+    // labels after the first key of each group of targets, shortest first
+    let mut group_labels: Vec<(char, std::vec::IntoIter<String>)> = Vec::new();
+    for key in &keyset.keys {
+      let count = firsts.iter().filter(|first| **first == Some(*key)).count();
+      let labels = match count {
+        0 => continue,
+        1 => vec![key.to_string()],
+        _ => {
+          let mut trie = Self::default();
+          for _ in 0..count {
+            trie.grow_after(keyset, Some(*key));
+          }
+          trie
+            .labels()
+            .into_iter()
+            .map(|label| format!("{key}{label}"))
+            .collect()
+        }
+      };
+      group_labels.push((*key, labels.into_iter()));
+    }
+
+    firsts
+      .iter()
+      .map(|first| match first {
+        Some(first) => group_labels
+          .iter_mut()
+          .find(|(key, _)| key == first)
+          .and_then(|(_, labels)| labels.next()),
+        None => rest_labels.next(),
+      })
+      .collect()
+  }
+
+  /// Labels of targets belonging to `groups`, in their order.
+  ///
+  /// Targets of the same group share the label of their group, followed by the labels of a sub-trie after it if there
+  /// are several of them. Groups get the shortest labels in the order they first appear. Empty if the keyset doesn’t
+  /// allow enough labels.
+  pub fn grouped_labels<T: PartialEq>(keyset: &Keyset, groups: &[T]) -> Vec<String> {
+    let mut distinct: Vec<&T> = Vec::new();
+    for group in groups {
+      if !distinct.contains(&group) {
+        distinct.push(group);
+      }
+    }
+
+    let mut trie = Self::default();
+    trie.grow_repeatedly(distinct.len(), keyset);
+    let group_labels = trie.labels();
+
+    let mut labels: Vec<_> = distinct
+      .iter()
+      .zip(&group_labels)
+      .map(|(group, prefix)| {
+        let count = groups.iter().filter(|g| g == group).count();
+        if count == 1 {
+          return vec![prefix.clone()].into_iter();
+        }
+
+        let mut trie = Self::default();
+        for _ in 0..count {
+          trie.grow_after(keyset, prefix.chars().last());
+        }
+        let labels: Vec<_> = trie
+          .labels()
+          .into_iter()
+          .map(|label| format!("{prefix}{label}"))
+          .collect();
+        labels.into_iter()
+      })
+      .collect();
+
+    groups
+      .iter()
+      .map(|group| {
+        let i = distinct.iter().position(|g| *g == group)?;
+        labels.get_mut(i)?.next()
+      })
+      .collect::<Option<_>>()
+      .unwrap_or_default()
+  }
+
+  pub fn labels(&self) -> Vec<String> {
+    self.iter_labels().collect()
+  }
+
+  /// Iterate over the labels, depth-first.
+  pub fn iter_labels(&self) -> Labels<'_> {
+    Labels {
+      stack: vec![self.below.iter()],
+      label: String::new(),
+    }
+  }
+}
+
+/// Labels of a [`Trie`], built by pushing and popping keys on the same buffer.
+#[derive(Debug)]
+pub struct Labels<'a> {
+  /// Nodes left to visit at each depth.
+  stack: Vec<std::slice::Iter<'a, Trie>>,
+
+  /// Keys of the nodes being visited.
+  label: String,
+}
+
+impl Iterator for Labels<'_> {
+  type Item = String;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      let nodes = self.stack.last_mut()?;
+
+      match nodes.next() {
+        Some(node) if node.below.is_empty() => {
+          self.label.push(node.key);
+          let label = self.label.clone();
+          self.label.pop();
+          return Some(label);
+        }
+
+        Some(node) => {
+          self.label.push(node.key);
+          self.stack.push(node.below.iter());
+        }
+
+        // done with this node, so go back to its parent
+        None => {
+          self.stack.pop();
+          self.label.pop();
+        }
+      }
+    }
+  }
+}
+
+/// Strategy giving labels to selections.
+///
+/// Strategies are selected at runtime from the [`Config`] of a hop; see [`App`].
+pub trait LabelAllocator {
+  /// Labels of `sels`, in the same order.
+  ///
+  /// `buffer` is the buffer text, if read. There are fewer labels than selections if the keyset does not allow enough
+  /// of them.
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], buffer: Option<&Buffer>) -> Vec<String>;
+}
+
+/// Labels grown from a [`Trie`]; the first selections get the shortest labels.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrieAllocator;
+
+impl LabelAllocator for TrieAllocator {
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], _: Option<&Buffer>) -> Vec<String> {
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(sels.len(), keyset);
+
+    let mut labels = Vec::with_capacity(sels.len());
+    labels.extend(trie.iter_labels());
+    labels
+  }
+}
+
+/// Labels of exactly `len` keys; see [`Trie::fixed_labels`].
+#[derive(Clone, Copy, Debug)]
+pub struct FixedLenAllocator {
+  pub len: usize,
+}
+
+impl LabelAllocator for FixedLenAllocator {
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], _: Option<&Buffer>) -> Vec<String> {
+    Trie::fixed_labels(keyset, self.len, sels.len())
+  }
+}
+
+/// Labels starting with the first character of their selection; see [`Trie::semantic_labels`].
+///
+/// Fall back to [`TrieAllocator`] without buffer, or if there are not enough keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SemanticAllocator;
+
+impl LabelAllocator for SemanticAllocator {
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], buffer: Option<&Buffer>) -> Vec<String> {
+    buffer
+      .and_then(|buffer| {
+        let firsts: Vec<_> = sels.iter().map(|sel| buffer.first_char(sel)).collect();
+        Trie::semantic_labels(keyset, &firsts)
+      })
+      .unwrap_or_else(|| TrieAllocator.allocate(keyset, sels, buffer))
+  }
+}
+
+/// Labels not starting with the character following their selection; see [`Trie::labels_avoiding`].
+///
+/// Fall back to [`TrieAllocator`] without buffer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AvoidConflictsAllocator;
+
+impl LabelAllocator for AvoidConflictsAllocator {
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], buffer: Option<&Buffer>) -> Vec<String> {
+    let Some(buffer) = buffer else {
+      return TrieAllocator.allocate(keyset, sels, None);
+    };
+
+    let forbidden: Vec<_> = sels
+      .iter()
+      .map(|sel| buffer.next_char(sel).into_iter().collect())
+      .collect();
+    Trie::labels_avoiding(keyset, &forbidden)
+  }
+}
+
+/// Labels sharing their first keys with the selections starting on the same line; see [`Trie::grouped_labels`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LineAllocator;
+
+impl LabelAllocator for LineAllocator {
+  fn allocate(&self, keyset: &Keyset, sels: &[Sel], _: Option<&Buffer>) -> Vec<String> {
+    let lines: Vec<_> = sels.iter().map(|sel| sel.start.line).collect();
+    Trie::grouped_labels(keyset, &lines)
+  }
+}
+
+/// Position in the buffer.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Pos {
+  pub line: usize,
+  pub col: usize,
+}
+
+impl FromStr for Pos {
+  type Err = SelError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || SelError::Pos(s.to_owned());
+    let (line, col) = s.split_once('.').ok_or_else(invalid)?;
+    let line = line.parse().map_err(|_| invalid())?;
+    let col = col.parse().map_err(|_| invalid())?;
+
+    Ok(Pos { line, col })
+  }
+}
+
+impl Pos {
+  /// Distance to another position, as a (lines, columns) pair.
+  ///
+  /// Comparing distances favors positions on closer lines first, then closer columns.
+  pub fn distance(&self, other: &Pos) -> (usize, usize) {
+    (self.line.abs_diff(other.line), self.col.abs_diff(other.col))
+  }
+}
+
+/// Location in a file, as printed by `grep -n --column`; e.g. `src/main.rs:12:5`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location<'a> {
+  pub file: &'a str,
+  pub line: usize,
+  pub col: Option<usize>,
+}
+
+impl<'a> Location<'a> {
+  /// Location starting `s` — followed by a colon, if anything — along with its length in bytes.
+  pub fn parse_prefix(s: &'a str) -> Option<(Self, usize)> {
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+
+    let (file, rest) = s.split_once(':')?;
+    if file.is_empty() {
+      return None;
+    }
+
+    let line_len = digits(rest);
+    let line = rest[..line_len].parse().ok()?;
+    let mut len = file.len() + 1 + line_len;
+    let mut rest = &rest[line_len..];
+
+    let col = match rest.strip_prefix(':') {
+      Some(after) if digits(after) > 0 => {
+        let col_len = digits(after);
+        rest = &after[col_len..];
+        len += 1 + col_len;
+        after[..col_len].parse().ok()
+      }
+      _ => None,
+    };
+
+    (rest.is_empty() || rest.starts_with(':')).then_some((Location { file, line, col }, len))
+  }
+}
+
+/// Words ending with a period which don’t end sentences; see [`ends_sentence`].
+const ABBREVIATIONS: &[&str] = &[
+  "cf.", "dr.", "e.g.", "i.e.", "jr.", "mr.", "mrs.", "ms.", "no.", "prof.", "sr.", "st.", "vs.",
+];
+
+/// Whether `word` ends a sentence.
+///
+/// It does if it ends with `.`, `?` or `!` — closing quotes and brackets aside — unless it is an abbreviation, either
+/// known or made of single letters followed by periods; e.g. `J.` or `U.S.`.
+fn ends_sentence(word: &str) -> bool {
+  let word = word.trim_start_matches(|c: char| "\"'([{“‘«".contains(c));
+  let word = word.trim_end_matches(|c: char| "\"')]}”’»".contains(c));
+
+  if word.ends_with(['?', '!']) {
+    return true;
+  }
+
+  if !word.ends_with('.') {
+    return false;
+  }
+
+  let is_initials = word
+    .split_terminator('.')
+    .all(|letters| letters.chars().count() == 1 && letters.chars().all(char::is_alphabetic));
+
+  !is_initials && !ABBREVIATIONS.contains(&word.to_lowercase().as_str())
+}
+
+/// Length in bytes of the number literal starting `s`, which starts with a digit.
+///
+/// Numbers are either hexadecimal, binary or octal — with a `0x`, `0b` or `0o` prefix — or decimal, with an optional
+/// fractional part. Digits can be separated by underscores.
+fn number_len(s: &str) -> usize {
+  let bytes = s.as_bytes();
+  let (is_digit, mut len): (fn(&u8) -> bool, _) = match bytes {
+    [b'0', b'x' | b'X', c, ..] if c.is_ascii_hexdigit() => (u8::is_ascii_hexdigit, 2),
+    [b'0', b'b' | b'B', b'0' | b'1', ..] => (|c| matches!(c, b'0' | b'1'), 2),
+    [b'0', b'o' | b'O', b'0'..=b'7', ..] => (|c| matches!(c, b'0'..=b'7'), 2),
+    _ => (u8::is_ascii_digit, 0),
+  };
+  let digits = |from: usize| {
+    bytes[from..]
+      .iter()
+      .take_while(|&c| is_digit(c) || *c == b'_')
+      .count()
+  };
+
+  len += digits(len);
+
+  // only decimal numbers have a fractional part
+  if len == digits(0)
+    && bytes.get(len) == Some(&b'.')
+    && bytes.get(len + 1).is_some_and(u8::is_ascii_digit)
+  {
+    len += 1 + digits(len + 1);
+  }
+
+  len
+}
+
+/// Bytes of the first and last characters of every subword of `word`.
+///
+/// Subwords are separated by underscores, and start at an uppercase letter following a lowercase one or a digit, or
+/// followed by a lowercase one; e.g. `HTTPServer_utf8Len` is made of `HTTP`, `Server`, `utf8` and `Len`.
+fn subword_bounds(word: &str) -> Vec<(usize, usize)> {
+  let chars: Vec<_> = word.char_indices().collect();
+  let mut bounds = Vec::new();
+
+  // index in chars of the start of the subword being scanned, if any
+  let mut start: Option<usize> = None;
+
+  for (j, &(_, c)) in chars.iter().enumerate() {
+    if c == '_' {
+      if let Some(start) = start.take() {
+        bounds.push((chars[start].0, chars[j - 1].0));
+      }
+
+      continue;
+    }
+
+    if let Some(s) = start {
+      let prev = chars[j - 1].1;
+      let next = chars.get(j + 1).map(|&(_, c)| c);
+      let is_start = c.is_uppercase()
+        && ((prev.is_lowercase() || prev.is_numeric())
+          || (prev.is_uppercase() && next.is_some_and(char::is_lowercase)));
+
+      if is_start {
+        bounds.push((chars[s].0, chars[j - 1].0));
+        start = None;
+      }
+    }
+
+    start.get_or_insert(j);
+  }
+
+  if let (Some(start), Some(&(last, _))) = (start, chars.last()) {
+    bounds.push((chars[start].0, last));
+  }
+
+  bounds
+}
+
+/// Bytes of the first and last characters of the URL or file path in `token`, if any.
+///
+/// URLs start with a scheme followed by `://`. Paths contain a slash, and either start with `/`, `./`, `../` or `~/`, or
+/// end with a file name with an extension; e.g. `src/main.rs:12`. Quotes, brackets and punctuation around them are left
+/// out.
+fn link_bounds(token: &str) -> Option<(usize, usize)> {
+  let first = token.find(|c: char| !"\"'([{<`".contains(c))?;
+  let link = token[first..].trim_end_matches(|c: char| ".,;:!?\"')]}>`".contains(c));
+  let (last, _) = link.char_indices().last()?;
+
+  let is_url = link.split_once("://").is_some_and(|(scheme, rest)| {
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+      && chars.all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+      && !rest.is_empty()
+  });
+
+  let is_path = || {
+    let path = link.split(':').next().unwrap_or_default();
+    let name = path.rsplit('/').next().unwrap_or_default();
+
+    path.contains('/')
+      && path.contains(|c: char| !"/.~".contains(c))
+      && (["/", "./", "../", "~/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+        || name.trim_start_matches('.').contains('.'))
+  };
+
+  (is_url || (!link.contains("://") && is_path())).then_some((first, first + last))
+}
+
+/// Range of lines in the buffer, both ends included.
+#[derive(Clone, Copy, Debug)]
+pub struct LineRange {
+  first: usize,
+  last: usize,
+}
+
+impl FromStr for LineRange {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (first, last) = s
+      .split_once(',')
+      .ok_or_else(|| format!("missing comma in line range: {s}"))?;
+    let first = first
+      .parse()
+      .map_err(|_| format!("invalid first line: {first}"))?;
+    let last = last
+      .parse()
+      .map_err(|_| format!("invalid last line: {last}"))?;
+
+    Ok(LineRange { first, last })
+  }
+}
+
+impl LineRange {
+  /// Lines of a Kakoune `window_range`: the line and column of the top left corner of the window, from 0, and its
+  /// height and width.
+  pub fn from_window_range(s: &str) -> Result<Self, String> {
+    let mut numbers = s.split_whitespace().map(|n| n.parse::<usize>());
+    match (numbers.next(), numbers.next(), numbers.next()) {
+      (Some(Ok(line)), Some(Ok(_)), Some(Ok(height))) => Ok(LineRange {
+        first: line + 1,
+        last: line + height,
+      }),
+      _ => Err(format!("invalid window range: {s}")),
+    }
+  }
+
+  pub fn contains(&self, line: usize) -> bool {
+    (self.first..=self.last).contains(&line)
+  }
+
+  /// Selections of the first column of every line in the range.
+  pub fn line_starts(&self) -> Vec<Sel> {
+    (self.first..=self.last)
+      .map(|line| Sel::new(Pos { line, col: 1 }, Pos { line, col: 1 }))
+      .collect()
+  }
+}
+
+/// Selections saved in a register, as with `Z`.
+#[derive(Clone, Debug)]
+pub struct Mark {
+  /// Name of the register holding the mark.
+  pub register: String,
+
+  /// Buffer the selections were saved in.
+  pub buffile: String,
+
+  /// Timestamp of the buffer when the selections were saved.
+  pub timestamp: usize,
+
+  /// Main selection of the mark.
+  pub main: Sel,
+}
+
+impl FromStr for Mark {
+  type Err = String;
+
+  /// Parse the name of a register followed by its contents; e.g. `a src/main.rs@12@0 1.1,1.4 3.2,3.2`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (register, contents) = s
+      .split_once(' ')
+      .ok_or_else(|| format!("missing register contents: {s}"))?;
+
+    // the buffer name may contain spaces, but not the selections following it
+    let tokens: Vec<_> = contents.split(' ').collect();
+    let sels_len = tokens
+      .iter()
+      .rev()
+      .take_while(|token| token.parse::<Sel>().is_ok())
+      .count();
+    let (desc, sels) = tokens.split_at(tokens.len() - sels_len);
+
+    let desc = desc.join(" ");
+    let mut parts = desc.rsplitn(3, '@');
+    let (Some(main), Some(timestamp), Some(buffile)) = (parts.next(), parts.next(), parts.next())
+    else {
+      return Err(format!("not a mark: {contents}"));
+    };
+    let main: usize = main
+      .parse()
+      .map_err(|_| format!("invalid main selection index: {main}"))?;
+    let timestamp = timestamp
+      .parse()
+      .map_err(|_| format!("invalid timestamp: {timestamp}"))?;
+    let main = sels
+      .get(main)
+      .and_then(|sel| sel.parse().ok())
+      .ok_or_else(|| format!("missing main selection: {contents}"))?;
+
+    Ok(Mark {
+      register: register.to_owned(),
+      buffile: buffile.to_owned(),
+      timestamp,
+      main,
+    })
+  }
+}
+
+/// Named position in a buffer, such as a function or a type definition.
+#[derive(Clone, Debug)]
+pub struct Symbol {
+  pub pos: Pos,
+  pub name: String,
+}
+
+impl FromStr for Symbol {
+  type Err = String;
+
+  /// Parse a position followed by a tab and the name of the symbol; e.g. `12.4\tmain`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (pos, name) = s
+      .split_once('\t')
+      .ok_or_else(|| format!("missing symbol name: {s}"))?;
+    let pos = pos.parse().map_err(|err| format!("{err}"))?;
+
+    Ok(Symbol {
+      pos,
+      name: name.to_owned(),
+    })
+  }
+}
+
+/// Parse symbols, one per line, ignoring lines not holding any; see [`Symbol`].
+pub fn parse_symbols(s: &str) -> Vec<Symbol> {
+  s.lines().filter_map(|line| line.parse().ok()).collect()
+}
+
+/// Parse marks, one per line, ignoring lines not holding any; see [`Mark`].
+pub fn parse_marks(s: &str) -> Vec<Mark> {
+  s.lines().filter_map(|line| line.parse().ok()).collect()
+}
+
+/// Parse space separated selections, failing on the first invalid one with its position in the list.
+pub fn parse_sels(s: &str) -> Result<Vec<Sel>, SelError> {
+  parse_sel_list(s.split_whitespace())
+}
+
+/// Parse NUL separated selections, as read with `--sels0`; empty entries are ignored.
+///
+/// Entries are taken as they are, so that the names of their buffers can contain spaces.
+pub fn parse_sels0(s: &str) -> Result<Vec<Sel>, SelError> {
+  parse_sel_list(s.split('\0').filter(|entry| !entry.is_empty()))
+}
+
+fn parse_sel_list<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Vec<Sel>, SelError> {
+  tokens
+    .enumerate()
+    .map(|(i, token)| {
+      token.parse().map_err(|source| SelError::InList {
+        index: i + 1,
+        token: token.to_owned(),
+        source: Box::new(source),
+      })
+    })
+    .collect()
+}
+
+/// Reader of the binary encoding; see [`parse_binary`].
+struct BinaryReader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> BinaryReader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], SelError> {
+    if self.bytes.len() < len {
+      return Err(SelError::Binary("truncated input"));
+    }
+
+    let (taken, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn u16(&mut self) -> Result<u16, SelError> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, SelError> {
+    let bytes = self.take(4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  /// A `u16` length followed by that many bytes of UTF-8.
+  fn str(&mut self) -> Result<&'a str, SelError> {
+    let len = self.u16()?;
+    std::str::from_utf8(self.take(len.into())?).map_err(|_| SelError::Binary("invalid UTF-8"))
+  }
+
+  fn pos(&mut self) -> Result<Pos, SelError> {
+    Ok(Pos {
+      line: self.u32()? as usize,
+      col: self.u32()? as usize,
+    })
+  }
+}
+
+/// Parse selections, and the labels that may follow them, in the binary encoding read with `--binary`.
+///
+/// Integers are little endian. Selections come first, as a `u32` count followed by each selection: the line and column
+/// of its anchor and of its cursor, as `u32`, then the name of its buffer, empty for the current one. Labels may follow,
+/// as a `u32` count followed by each label. Names and labels are a `u16` length followed by that many bytes of UTF-8.
+///
+/// The encoding skips the parsing of the text one, which is measurable with tens of thousands of selections; see
+/// [`encode_binary`].
+pub fn parse_binary(bytes: &[u8]) -> Result<(Vec<Sel>, Vec<String>), SelError> {
+  let mut reader = BinaryReader { bytes };
+
+  let count = reader.u32()?;
+  let mut sels = Vec::new();
+  for _ in 0..count {
+    let anchor = reader.pos()?;
+    let cursor = reader.pos()?;
+    let sel = Sel::new(anchor, cursor);
+    sels.push(match reader.str()? {
+      "" => sel,
+      buffile => sel.in_buffer(buffile),
+    });
+  }
+
+  let mut labels = Vec::new();
+  if !reader.bytes.is_empty() {
+    let count = reader.u32()?;
+    for _ in 0..count {
+      labels.push(reader.str()?.to_owned());
+    }
+  }
+
+  if !reader.bytes.is_empty() {
+    return Err(SelError::Binary("trailing bytes"));
+  }
+
+  Ok((sels, labels))
+}
+
+/// Encode selections and labels for [`parse_binary`]; labels are left out if there are none.
+///
+/// Lines and columns must fit in a `u32`, and names and labels in `u16::MAX` bytes.
+pub fn encode_binary(sels: &[Sel], labels: &[String]) -> Vec<u8> {
+  fn str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend((s.len() as u16).to_le_bytes());
+    bytes.extend(s.as_bytes());
+  }
+
+  let mut bytes = Vec::new();
+  bytes.extend((sels.len() as u32).to_le_bytes());
+  for sel in sels {
+    for pos in [sel.anchor(), sel.cursor()] {
+      bytes.extend((pos.line as u32).to_le_bytes());
+      bytes.extend((pos.col as u32).to_le_bytes());
+    }
+    str(&mut bytes, sel.buffile().unwrap_or_default());
+  }
+
+  if !labels.is_empty() {
+    bytes.extend((labels.len() as u32).to_le_bytes());
+    for label in labels {
+      str(&mut bytes, label);
+    }
+  }
+
+  bytes
+}
+
+/// Shuffle `sels` with a xorshift generator seeded with `seed`.
+fn shuffle(sels: &mut [Sel], seed: u64) {
+  let mut state = seed | 1;
+
+  for i in (1..sels.len()).rev() {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    sels.swap(i, (state % (i as u64 + 1)) as usize);
+  }
+}
+
+/// Selections starting at distinct positions, the widest one being kept among those starting at the same one.
+///
+/// Their labels would be stacked on top of each other otherwise. The order of the selections is kept.
+fn dedup_sels(sels: Vec<Sel>) -> Vec<Sel> {
+  let mut deduped: Vec<Sel> = Vec::with_capacity(sels.len());
+  let mut starts = BTreeMap::new();
+
+  for sel in sels {
+    match starts.entry((sel.buffile.clone(), sel.start.clone())) {
+      Entry::Vacant(entry) => {
+        entry.insert(deduped.len());
+        deduped.push(sel);
+      }
+
+      Entry::Occupied(entry) => {
+        let kept = &mut deduped[*entry.get()];
+        if kept.end < sel.end {
+          *kept = sel;
+        }
+      }
+    }
+  }
+
+  deduped
+}
+
+/// Why a position or a selection cannot be parsed.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum SelError {
+  /// Not two numbers separated by a period.
+  #[error("invalid position `{0}`; expected `line.column`")]
+  Pos(String),
+
+  /// Not two positions separated by a comma.
+  #[error("invalid selection `{0}`; expected `line.column,line.column`")]
+  Sel(String),
+
+  /// Binary input which does not follow the encoding of [`parse_binary`].
+  #[error("invalid binary input: {0}")]
+  Binary(&'static str),
+
+  /// Invalid selection of a space separated list; `index` starts at 1.
+  #[error("selection {index} `{token}`: {source}")]
+  InList {
+    index: usize,
+    token: String,
+    source: Box<SelError>,
+  },
+}
+
+/// A selection in the buffer.
+///
+/// `start` is never after `end`, whatever the direction of the selection.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Sel {
+  start: Pos,
+  end: Pos,
+
+  /// Whether the cursor is on `start`, before the anchor.
+  reversed: bool,
+
+  /// Buffer the selection is in, if not the current one.
+  #[cfg_attr(
+    feature = "serde",
+    serde(default, skip_serializing_if = "Option::is_none")
+  )]
+  buffile: Option<String>,
+}
+
+impl FromStr for Sel {
+  type Err = SelError;
+
+  /// Parse a selection description, optionally prefixed with the name of its buffer; e.g. `*grep*@3.1,3.9`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    // buffer names may contain `@`, but not selection descriptions
+    let (buffile, desc) = match s.rsplit_once('@') {
+      Some((buffile, desc)) => (Some(buffile.to_owned()), desc),
+      None => (None, s),
+    };
+    let (anchor, cursor) = desc
+      .split_once(',')
+      .ok_or_else(|| SelError::Sel(s.to_owned()))?;
+
+    Ok(Sel {
+      buffile,
+      ..Sel::new(anchor.parse()?, cursor.parse()?)
+    })
+  }
+}
+
+impl Sel {
+  /// Selection from `anchor` to `cursor`, in any direction.
+  pub fn new(anchor: Pos, cursor: Pos) -> Self {
+    if cursor < anchor {
+      Sel {
+        start: cursor,
+        end: anchor,
+        reversed: true,
+        buffile: None,
+      }
+    } else {
+      Sel {
+        start: anchor,
+        end: cursor,
+        reversed: false,
+        buffile: None,
+      }
+    }
+  }
+
+  /// Buffer the selection is in, if not the current one.
+  pub fn buffile(&self) -> Option<&str> {
+    self.buffile.as_deref()
+  }
+
+  /// This selection in the buffer `buffile`.
+  pub fn in_buffer(self, buffile: impl Into<String>) -> Self {
+    Sel {
+      buffile: Some(buffile.into()),
+      ..self
+    }
+  }
+
+  pub fn anchor(&self) -> &Pos {
+    if self.reversed {
+      &self.end
+    } else {
+      &self.start
+    }
+  }
+
+  pub fn cursor(&self) -> &Pos {
+    if self.reversed {
+      &self.start
+    } else {
+      &self.end
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    let buffile = self
+      .buffile
+      .as_deref()
+      .map(|buffile| format!(r#","buffile":{}"#, json_str(buffile)))
+      .unwrap_or_default();
+
+    format!(
+      r#"{{"start":{{"line":{},"col":{}}},"end":{{"line":{},"col":{}}},"reversed":{}{buffile}}}"#,
+      self.start.line, self.start.col, self.end.line, self.end.col, self.reversed
+    )
+  }
+
+  /// This selection with its cursor on `handle`.
+  ///
+  /// Reversed selections keep their direction.
+  pub fn with_cursor_on(&self, handle: Handle) -> Self {
+    let reversed = self.reversed || handle == Handle::Anchor;
+
+    Sel {
+      reversed,
+      ..self.clone()
+    }
+  }
+
+  /// Hash of the position of this selection, stable across invocations.
+  ///
+  /// This is FNV-1a, which unlike the hashers of the standard library is guaranteed not to change.
+  pub fn stable_hash(&self) -> u64 {
+    [self.start.line, self.start.col, self.end.line, self.end.col]
+      .iter()
+      .flat_map(|&n| (n as u64).to_le_bytes())
+      .chain(self.buffile.iter().flat_map(|buffile| buffile.bytes()))
+      .fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+      })
+  }
+
+  /// Single-character selection at `anchor` in this selection.
+  ///
+  /// Columns are bytes, so the center of a selection may fall inside a multibyte character, which Kakoune adjusts.
+  pub fn collapsed(&self, anchor: Anchor) -> Self {
+    let pos = match anchor {
+      Anchor::Start => self.start.clone(),
+      Anchor::End => self.end.clone(),
+      Anchor::Center if self.is_multiline() => {
+        let line = (self.start.line + self.end.line) / 2;
+        let col = if line == self.start.line {
+          self.start.col
+        } else {
+          1
+        };
+        Pos { line, col }
+      }
+      Anchor::Center => Pos {
+        line: self.start.line,
+        col: (self.start.col + self.end.col) / 2,
+      },
+    };
+
+    Sel {
+      buffile: self.buffile.clone(),
+      ..Sel::new(pos.clone(), pos)
+    }
+  }
+
+  /// Whether this selection and `other` share at least a character.
+  pub fn overlaps(&self, other: &Sel) -> bool {
+    self.buffile == other.buffile && self.start <= other.end && other.start <= self.end
+  }
+
+  /// Whether this selection spans several lines.
+  pub fn is_multiline(&self) -> bool {
+    self.start.line != self.end.line
+  }
+
+  pub fn to_str(&self) -> String {
+    self.to_string()
+  }
+
+  /// Kakoune description of this selection, without its buffer.
+  pub fn desc(&self) -> String {
+    format!(
+      "{anchor_line}.{anchor_col},{cursor_line}.{cursor_col}",
+      anchor_line = self.anchor().line,
+      anchor_col = self.anchor().col,
+      cursor_line = self.cursor().line,
+      cursor_col = self.cursor().col,
+    )
+  }
+}
+
+impl Display for Sel {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if let Some(buffile) = &self.buffile {
+      write!(f, "{buffile}@")?;
+    }
+
+    write!(
+      f,
+      "{anchor_line}.{anchor_col},{cursor_line}.{cursor_col}",
+      anchor_line = self.anchor().line,
+      anchor_col = self.anchor().col,
+      cursor_line = self.cursor().line,
+      cursor_col = self.cursor().col,
+    )
+  }
+}
+
+/// Buffer text read on the standard input.
+#[derive(Clone, Debug)]
+pub struct Buffer {
+  /// Line number of the first line of `text`.
+  first_line: usize,
+  text: String,
+
+  /// Byte offset of the start of each line.
+  line_starts: Vec<usize>,
+}
+
+impl Buffer {
+  pub fn new(first_line: usize, text: impl Into<String>) -> Self {
+    let text = text.into();
+    let line_starts = iter::once(0)
+      .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+      .collect();
+
+    Self {
+      first_line,
+      text,
+      line_starts,
+    }
+  }
+
+  /// Line `line` of the buffer, without its line ending, as with [`str::lines`].
+  pub fn line(&self, line: usize) -> Option<&str> {
+    let i = line.checked_sub(self.first_line)?;
+    let start = *self.line_starts.get(i)?;
+
+    // nothing after the last line ending
+    if start == self.text.len() {
+      return None;
+    }
+
+    match self.line_starts.get(i + 1) {
+      Some(next) => {
+        let line = &self.text[start..next - 1];
+        Some(line.strip_suffix('\r').unwrap_or(line))
+      }
+      None => Some(&self.text[start..]),
+    }
+  }
+
+  /// Selection in the buffer of the line at index `line` (starting from `0`), from byte `start` to byte `end`, both
+  /// included.
+  fn sel(&self, line: usize, start: usize, end: usize) -> Sel {
+    let line = self.first_line + line;
+
+    Sel::new(
+      Pos {
+        line,
+        col: start + 1,
+      },
+      Pos { line, col: end + 1 },
+    )
+  }
+
+  /// Columns of the `count` text units on the `handle` side of `sel`.
+  ///
+  /// Columns are byte offsets relative to the start of the line, and delimit the units; a label character is displayed
+  /// on each of them. Labels of multi-line selections are always on the start side. If `overflow` is set, units past
+  /// the selection on the `handle` side are used when the selection is too narrow.
+  ///
+  /// Empty if `sel` is not in the buffer.
+  pub fn columns(
+    &self,
+    sel: &Sel,
+    unit: ColumnUnit,
+    count: usize,
+    handle: Handle,
+    overflow: bool,
+  ) -> Vec<usize> {
+    let handle = if sel.is_multiline() {
+      Handle::Anchor
+    } else {
+      handle
+    };
+
+    let Some(line) = self.line(sel.start.line) else {
+      return Vec::new();
+    };
+
+    // the end column of a selection is the first byte of its last character
+    let start = sel.start.col.saturating_sub(1);
+    let end = if !sel.is_multiline() {
+      let last = sel.end.col.saturating_sub(1);
+      line
+        .get(last..)
+        .and_then(|s| s.chars().next())
+        .map_or(line.len(), |c| last + c.len_utf8())
+    } else {
+      line.len()
+    };
+    let (start, end) = match (overflow, handle) {
+      (false, _) => (start, end),
+      (true, Handle::Anchor) => (start, line.len()),
+      (true, Handle::Cursor) => (0, end),
+    };
+    let Some(text) = line.get(start..end) else {
+      return Vec::new();
+    };
+
+    let units: Vec<usize> = match unit {
+      ColumnUnit::Byte => vec![1; text.len()],
+      ColumnUnit::Char => text.chars().map(char::len_utf8).collect(),
+      ColumnUnit::Grapheme => text.graphemes(true).map(str::len).collect(),
+    };
+    let units = match handle {
+      Handle::Anchor => &units[..count.min(units.len())],
+      Handle::Cursor => &units[units.len().saturating_sub(count)..],
+    };
+    let first = match handle {
+      Handle::Anchor => start,
+      Handle::Cursor => end - units.iter().sum::<usize>(),
+    };
+
+    iter::once(first)
+      .chain(units.iter().scan(first, |col, len| {
+        *col += len;
+        Some(*col)
+      }))
+      .collect()
+  }
+
+  /// First character of `sel`.
+  pub fn first_char(&self, sel: &Sel) -> Option<char> {
+    let line = self.line(sel.start.line)?;
+
+    line.get(sel.start.col.checked_sub(1)?..)?.chars().next()
+  }
+
+  /// Text from the start of `sel` to the end of its line, up to `len` characters.
+  pub fn text_at(&self, sel: &Sel, len: usize) -> &str {
+    let Some(text) = self
+      .line(sel.start.line)
+      .and_then(|line| line.get(sel.start.col.checked_sub(1)?..))
+    else {
+      return "";
+    };
+
+    match text.char_indices().nth(len) {
+      Some((end, _)) => &text[..end],
+      None => text,
+    }
+  }
+
+  /// Character right after `sel`, if any on its line.
+  pub fn next_char(&self, sel: &Sel) -> Option<char> {
+    let line = self.line(sel.end.line)?;
+    let mut chars = line.get(sel.end.col.checked_sub(1)?..)?.chars();
+
+    chars.next()?;
+    chars.next()
+  }
+
+  /// Display widths of the text units between `columns`, on the start line of `sel`.
+  ///
+  /// Double-width characters, such as CJK ones, have a width of `2`.
+  pub fn widths(&self, sel: &Sel, columns: &[usize]) -> Vec<usize> {
+    let line = self.line(sel.start.line).unwrap_or_default();
+
+    columns
+      .windows(2)
+      .map(|cols| line.get(cols[0]..cols[1]).map_or(1, |unit| unit.width()))
+      .collect()
+  }
+
+  /// Selections of the first column of every non-empty line in the buffer.
+  pub fn line_starts(&self) -> Vec<Sel> {
+    (self.first_line..)
+      .map_while(|line| Some((line, self.line(line)?)))
+      .filter(|(_, text)| !text.is_empty())
+      .map(|(line, _)| Sel::new(Pos { line, col: 1 }, Pos { line, col: 1 }))
+      .collect()
+  }
+
+  /// Text of a single-line selection; empty if it spans several lines.
+  pub fn sel_text(&self, sel: &Sel) -> &str {
+    let Some(line) = self
+      .line(sel.start.line)
+      .filter(|_| sel.start.line == sel.end.line)
+    else {
+      return "";
+    };
+
+    // the end column is the first byte of the last character
+    let end = sel.end.col.saturating_sub(1);
+    let end = line
+      .get(end..)
+      .and_then(|rest| rest.chars().next())
+      .map_or(line.len(), |c| end + c.len_utf8());
+
+    line
+      .get(sel.start.col.saturating_sub(1)..end)
+      .unwrap_or_default()
+  }
+
+  /// Selections of the `file:line:col` locations starting the lines of a grep buffer; see [`Location`].
+  pub fn grep_locations(&self) -> Vec<Sel> {
+    (self.first_line..)
+      .map_while(|line| Some((line, self.line(line)?)))
+      .filter_map(|(line, text)| {
+        let (_, len) = Location::parse_prefix(text)?;
+        Some(Sel::new(Pos { line, col: 1 }, Pos { line, col: len }))
+      })
+      .collect()
+  }
+
+  /// Selections of every word in the buffer.
+  ///
+  /// A word is a sequence of alphanumeric characters and underscores.
+  pub fn words(&self) -> Vec<Sel> {
+    let mut sels = Vec::new();
+
+    for (i, line) in self.text.lines().enumerate() {
+      // (start, end) columns of the word being scanned, if any
+      let mut word: Option<(usize, usize)> = None;
+
+      for (col, c) in line.char_indices() {
+        if c.is_alphanumeric() || c == '_' {
+          word = Some(word.map_or((col, col), |(start, _)| (start, col)));
+        } else if let Some((start, end)) = word.take() {
+          sels.push(self.sel(i, start, end));
+        }
+      }
+
+      if let Some((start, end)) = word {
+        sels.push(self.sel(i, start, end));
+      }
+    }
+
+    sels
+  }
+
+  /// Selections of every URL and file path in the buffer; see [`link_bounds`].
+  pub fn links(&self) -> Vec<Sel> {
+    let mut sels = Vec::new();
+
+    for (i, line) in self.text.lines().enumerate() {
+      // start of the whitespace separated token being scanned, if any
+      let mut token: Option<usize> = None;
+
+      for (col, c) in line.char_indices().chain(iter::once((line.len(), ' '))) {
+        if !c.is_whitespace() {
+          token.get_or_insert(col);
+        } else if let Some(start) = token.take() {
+          if let Some((first, last)) = link_bounds(&line[start..col]) {
+            sels.push(self.sel(i, start + first, start + last));
+          }
+        }
+      }
+    }
+
+    sels
+  }
+
+  /// Selections of every character of `set` in the buffer, or next to them if landing on a side of brackets.
+  ///
+  /// Several characters landing on the same position give a single selection.
+  pub fn brackets(&self, set: &str, land: Option<Land>) -> Vec<Sel> {
+    let mut sels = Vec::new();
+
+    for (i, line) in self.text.lines().enumerate() {
+      let chars: Vec<_> = line.char_indices().collect();
+
+      for (j, &(col, c)) in chars.iter().enumerate() {
+        if !set.contains(c) {
+          continue;
+        }
+
+        let next = || chars.get(j + 1).map_or(line.len(), |&(col, _)| col);
+        let prev = || chars.get(j.saturating_sub(1)).map_or(col, |&(col, _)| col);
+        let col = match (land, "([{<".contains(c), ")]}>".contains(c)) {
+          (Some(Land::Inside), true, _) | (Some(Land::Outside), _, true) => next(),
+          (Some(Land::Inside), _, true) | (Some(Land::Outside), true, _) => prev(),
+          _ => col,
+        };
+
+        sels.push(self.sel(i, col, col));
+      }
+    }
+
+    sels.sort_by(|a, b| a.start.cmp(&b.start));
+    sels.dedup_by(|a, b| a.start == b.start);
+    sels
+  }
+
+  /// Selections of every subword in the buffer; see [`subword_bounds`].
+  pub fn subwords(&self) -> Vec<Sel> {
+    self
+      .words()
+      .into_iter()
+      .flat_map(|word| {
+        let text = self.sel_text(&word);
+        let Pos { line, col } = word.start;
+
+        subword_bounds(text).into_iter().map(move |(start, end)| {
+          Sel::new(
+            Pos {
+              line,
+              col: col + start,
+            },
+            Pos {
+              line,
+              col: col + end,
+            },
+          )
+        })
+      })
+      .collect()
+  }
+
+  /// Selections of the start of every line made of whitespace only in the buffer.
+  pub fn blank_lines(&self) -> Vec<Sel> {
+    self
+      .text
+      .lines()
+      .enumerate()
+      .filter(|(_, line)| line.trim().is_empty())
+      .map(|(i, _)| self.sel(i, 0, 0))
+      .collect()
+  }
+
+  /// Selections of the first non-whitespace character of every line which indentation differs from the one of the
+  /// non-blank line before or after it, marking the boundaries of blocks.
+  pub fn indents(&self) -> Vec<Sel> {
+    // (index, indentation) of the non-blank lines
+    let lines: Vec<_> = self
+      .text
+      .lines()
+      .enumerate()
+      .filter(|(_, line)| !line.trim().is_empty())
+      .map(|(i, line)| (i, line.len() - line.trim_start().len()))
+      .collect();
+
+    lines
+      .iter()
+      .enumerate()
+      .filter(|&(j, &(_, indent))| {
+        let differs =
+          |other: Option<&(usize, usize)>| other.is_some_and(|&(_, other)| other != indent);
+        differs(j.checked_sub(1).and_then(|j| lines.get(j))) || differs(lines.get(j + 1))
+      })
+      .map(|(_, &(i, indent))| self.sel(i, indent, indent))
+      .collect()
+  }
+
+  /// Selections of every number literal in the buffer; see [`number_len`].
+  ///
+  /// Digits ending a word, such as in `utf8`, are not numbers. A minus sign is part of the number if not following a
+  /// word.
+  pub fn numbers(&self) -> Vec<Sel> {
+    let mut sels = Vec::new();
+
+    for (i, line) in self.text.lines().enumerate() {
+      let bytes = line.as_bytes();
+      let in_word =
+        |j: usize| bytes[j].is_ascii_alphanumeric() || bytes[j] == b'_' || !bytes[j].is_ascii();
+      let mut j = 0;
+
+      while j < bytes.len() {
+        if !bytes[j].is_ascii_digit() || (j > 0 && in_word(j - 1)) {
+          j += 1;
+          continue;
+        }
+
+        let start = match j.checked_sub(1) {
+          Some(minus) if bytes[minus] == b'-' && (minus == 0 || !in_word(minus - 1)) => minus,
+          _ => j,
+        };
+        let len = number_len(&line[j..]);
+
+        sels.push(self.sel(i, start, j + len - 1));
+        j += len;
+      }
+    }
+
+    sels
+  }
+
+  /// Selections of every occurrence of `c` in the buffer, or of the character before or after them; see
+  /// [`Buffer::occurrences`].
+  ///
+  /// Several occurrences landing on the same position give a single selection.
+  pub fn find_char(&self, c: char, till: Option<Till>) -> Vec<Sel> {
+    let mut sels = Vec::new();
+
+    for (i, line) in self.text.lines().enumerate() {
+      let chars: Vec<_> = line.char_indices().collect();
+
+      for (j, &(col, _)) in chars.iter().enumerate().filter(|(_, &(_, d))| d == c) {
+        let col = match till {
+          Some(Till::Before) => chars.get(j.saturating_sub(1)).map_or(col, |&(col, _)| col),
+          Some(Till::After) => chars.get(j + 1).map_or(line.len(), |&(col, _)| col),
+          None => col,
+        };
+
+        sels.push(self.sel(i, col, col));
+      }
+    }
+
+    sels.dedup_by(|a, b| a.start == b.start);
+    sels
+  }
+
+  /// Selections of the character at the display column of `cursor` on every other line of the buffer; see
+  /// [`display_spans`].
+  ///
+  /// Lines too short to reach that column are skipped. If the line of `cursor` is not in the buffer, its column is
+  /// taken as the display column.
+  pub fn column(&self, cursor: &Pos, tabstop: usize) -> Vec<Sel> {
+    let col = cursor.col.saturating_sub(1);
+    let target = self.line(cursor.line).map_or(col, |line| {
+      display_spans(line, tabstop)
+        .take_while(|&(byte, ..)| byte < col)
+        .map(|(_, _, width)| width)
+        .sum()
+    });
+
+    self
+      .text
+      .lines()
+      .enumerate()
+      .filter(|&(i, _)| self.first_line + i != cursor.line)
+      .filter_map(|(i, line)| {
+        display_spans(line, tabstop)
+          .find(|&(_, start, width)| start <= target && target < start + width)
+          .map(|(byte, ..)| self.sel(i, byte, byte))
+      })
+      .collect()
+  }
+
+  /// Selections of the start of the first line of every paragraph in the buffer.
+  ///
+  /// Paragraphs are separated by blank lines, which are made of whitespace only.
+  pub fn paragraphs(&self) -> Vec<Sel> {
+    let mut sels = Vec::new();
+    let mut blank = true;
+
+    for (i, line) in self.text.lines().enumerate() {
+      if blank && !line.trim().is_empty() {
+        sels.push(self.sel(i, 0, 0));
+      }
+
+      blank = line.trim().is_empty();
+    }
+
+    sels
+  }
+
+  /// Selections of every match of `regex` in the buffer.
+  ///
+  /// Matches may span several lines. Empty matches are ignored.
+  pub fn matches(&self, regex: &Regex) -> Vec<Sel> {
+    regex
+      .find_iter(&self.text)
+      .filter_map(|m| {
+        let (last, _) = m.as_str().char_indices().last()?;
+
+        Some(Sel::new(
+          self.pos_at(m.start()),
+          self.pos_at(m.start() + last),
+        ))
+      })
+      .collect()
+  }
+
+  /// Position of the byte at `offset` in the text.
+  fn pos_at(&self, offset: usize) -> Pos {
+    let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+
+    Pos {
+      line: self.first_line + line,
+      col: offset - self.line_starts[line] + 1,
+    }
+  }
+
+  /// Selections of the first character of every sentence in the buffer.
+  ///
+  /// Sentences end with a word ending with `.`, `?` or `!` — closing quotes and brackets aside — which is not an
+  /// abbreviation, such as `Dr.`, `e.g.` or an initial; paragraphs, separated by blank lines, start new sentences too.
+  /// The first word of the text is assumed to start a sentence.
+  pub fn sentences(&self) -> Vec<Sel> {
+    let mut sels = Vec::new();
+    let mut sentence_start = true;
+
+    // start of the word being scanned, if any, and line endings in the whitespace before it
+    let mut word: Option<usize> = None;
+    let mut line_endings = 0;
+
+    for (offset, c) in self
+      .text
+      .char_indices()
+      .chain(iter::once((self.text.len(), '\n')))
+    {
+      if !c.is_whitespace() {
+        if word.is_none() {
+          if sentence_start || line_endings > 1 {
+            sels.push(Sel::new(self.pos_at(offset), self.pos_at(offset)));
+          }
+
+          word = Some(offset);
+          line_endings = 0;
+        }
+
+        continue;
+      }
+
+      if c == '\n' {
+        line_endings += 1;
+      }
+
+      if let Some(start) = word.take() {
+        sentence_start = ends_sentence(&self.text[start..offset]);
+      }
+    }
+
+    sels
+  }
+
+  /// Selections of every occurrence of `needle` in the buffer.
+  pub fn occurrences(&self, needle: &str) -> Vec<Sel> {
+    let needle_last = needle.char_indices().last().map_or(0, |(i, _)| i);
+
+    self
+      .text
+      .lines()
+      .enumerate()
+      .flat_map(|(i, line)| {
+        line
+          .match_indices(needle)
+          .map(move |(col, _)| self.sel(i, col, col + needle_last))
+      })
+      .collect()
+  }
+}
+
+/// Standard input of a request.
+#[derive(Debug)]
+pub enum Input {
+  /// Our own standard input.
+  Stdin,
+
+  /// Standard input already read; forwarded by a client to the daemon, or kept to be logged.
+  Forwarded(Vec<u8>),
+
+  /// Standard input which could not be read upfront; reading it fails the request with this message.
+  Failed(String),
+}
+
+impl Input {
+  /// Read the input as text; invalid UTF-8, which buffers may contain, is replaced.
+  fn read(&mut self) -> Result<String, String> {
+    self
+      .read_bytes()
+      .map(|input| String::from_utf8_lossy(&input).into_owned())
+  }
+
+  /// Read the input as bytes, for the binary encoding.
+  fn read_bytes(&mut self) -> Result<Vec<u8>, String> {
+    match self {
+      Self::Stdin => {
+        let mut input = Vec::new();
+        io::stdin()
+          .read_to_end(&mut input)
+          .map_err(|err| format!("cannot read stdin: {err}"))?;
+        Ok(input)
+      }
+      Self::Forwarded(input) => Ok(std::mem::take(input)),
+      Self::Failed(message) => Err(message.clone()),
+    }
+  }
+}
+
+/// Parse space separated lists of comma separated numbers.
+fn parse_lists(s: &str) -> Result<Vec<Vec<usize>>, String> {
+  s.split_whitespace()
+    .map(|list| {
+      list
+        .split(',')
+        .map(|n| n.parse().map_err(|_| format!("invalid number: {n}")))
+        .collect()
+    })
+    .collect()
+}
+
+/// Number of characters of the text of each target to narrow the hints with.
+const NARROW_TEXT_LEN: usize = 32;
+
+/// Pattern of the markers hinted in TODO mode.
+pub const TODO_PATTERN: &str = r"\b(TODO|FIXME|HACK|XXX)\b";
+
+/// Parse space separated texts, in which backslashes, spaces and tabs are escaped as `\\`, `\s` and `\t`, and empty
+/// texts are written `\e`.
+fn parse_texts(s: &str) -> Vec<String> {
+  s.split(' ')
+    .filter(|text| !text.is_empty())
+    .map(|text| {
+      let mut unescaped = String::with_capacity(text.len());
+      let mut chars = text.chars();
+
+      while let Some(c) = chars.next() {
+        match c {
+          '\\' => match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('t') => unescaped.push('\t'),
+            Some('e') | None => (),
+            Some(c) => unescaped.push(c),
+          },
+          c => unescaped.push(c),
+        }
+      }
+
+      unescaped
+    })
+    .collect()
+}
+
+/// Inverse of [`parse_texts`].
+#[cfg(feature = "kakoune")]
+fn texts_str<'a>(texts: impl IntoIterator<Item = &'a str>) -> String {
+  let texts: Vec<_> = texts
+    .into_iter()
+    .map(|text| {
+      if text.is_empty() {
+        return "\\e".to_owned();
+      }
+
+      text
+        .replace('\\', "\\\\")
+        .replace(' ', "\\s")
+        .replace('\t', "\\t")
+    })
+    .collect();
+  texts.join(" ")
+}
+
+/// Keys following `typed` in the labels of `ranges`, along with the number of labels continuing with each of them, in
+/// the order they first appear.
+#[cfg(feature = "kakoune")]
+fn next_keys<'a>(
+  ranges: impl IntoIterator<Item = &'a ReplaceRange>,
+  typed: &str,
+) -> Vec<(char, usize)> {
+  let mut keys: Vec<(char, usize)> = Vec::new();
+
+  for range in ranges {
+    let Some(key) = range
+      .label
+      .strip_prefix(typed)
+      .and_then(|rest| rest.chars().next())
+    else {
+      continue;
+    };
+
+    match keys.iter_mut().find(|(k, _)| *k == key) {
+      Some((_, count)) => *count += 1,
+      None => keys.push((key, 1)),
+    }
+  }
+
+  keys
+}
+
+/// Inverse of [`parse_lists`].
+#[cfg(feature = "kakoune")]
+fn lists_str<'a>(lists: impl IntoIterator<Item = &'a [usize]>) -> String {
+  let lists: Vec<_> = lists
+    .into_iter()
+    .map(|list| {
+      let list: Vec<_> = list.iter().map(usize::to_string).collect();
+      list.join(",")
+    })
+    .collect();
+  lists.join(" ")
+}
+
+/// Label grapheme padded to cover `width` display columns, so that the text after it is not shifted.
+#[cfg(feature = "kakoune")]
+fn pad(grapheme: &str, width: usize) -> Cow<'_, str> {
+  if width <= 1 {
+    return Cow::Borrowed(grapheme);
+  }
+
+  Cow::Owned(format!("{grapheme}{}", " ".repeat(width - 1)))
+}
+
+/// Number of graphemes in `s`, without segmenting it if it is ASCII without carriage return.
+fn grapheme_count(s: &str) -> usize {
+  if s.is_ascii() && !s.contains('\r') {
+    s.len()
+  } else {
+    s.graphemes(true).count()
+  }
+}
+
+/// Quote a string as a single Kakoune word.
+#[cfg(feature = "kakoune")]
+fn kak_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Quote a string as a single Helix command argument.
+///
+/// Single-quoted arguments are not expanded by Helix, and quotes are doubled in them. Commands are written one per line,
+/// so line breaks are replaced with spaces.
+fn helix_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "''").replace(['\r', '\n'], " "))
+}
+
+/// Quote a string as a single POSIX shell word.
+pub fn sh_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Kakoune `%sh` expansion of `script`, with delimiters it cannot close early.
+///
+/// Kakoune has no escaping in `%` strings, so the first pair of brackets balanced in `script` is used, or else the first
+/// delimiter not in it.
+#[cfg(feature = "kakoune")]
+fn kak_sh(script: &str) -> String {
+  let balanced = |open, close| {
+    let mut depth = 0_usize;
+
+    for c in script.chars() {
+      if c == open {
+        depth += 1;
+      } else if c == close {
+        match depth.checked_sub(1) {
+          Some(d) => depth = d,
+          None => return false,
+        }
+      }
+    }
+
+    depth == 0
+  };
+
+  if let Some((open, close)) = [('{', '}'), ('(', ')'), ('[', ']'), ('<', '>')]
+    .into_iter()
+    .find(|&(open, close)| balanced(open, close))
+  {
+    return format!("%sh{open}{script}{close}");
+  }
+
+  let delim = ['|', '~', '@', '#', '^', '!', '=', '+', ':', '/']
+    .into_iter()
+    .find(|&delim| !script.contains(delim))
+    .unwrap_or('|');
+  format!("%sh{delim}{script}{delim}")
+}
+
+/// Append the uppercase variants of the lowercase keys of `keyset`, if not already in it.
+pub fn with_uppercase(mut keyset: Vec<char>) -> Vec<char> {
+  let uppercase: Vec<_> = keyset
+    .iter()
+    .filter(|key| key.is_lowercase())
+    .filter_map(|key| {
+      let mut upper = key.to_uppercase();
+      // some characters have no single character uppercase variant, such as ß
+      match (upper.next(), upper.next()) {
+        (Some(upper), None) if !keyset.contains(&upper) => Some(upper),
+        _ => None,
+      }
+    })
+    .collect();
+
+  keyset.extend(uppercase);
+  keyset
+}
+
+/// Number of labels of at most `len` keys of `keyset`.
+pub fn label_capacity(keyset: &[char], len: usize) -> usize {
+  u32::try_from(len)
+    .ok()
+    .and_then(|len| keyset.len().checked_pow(len))
+    .unwrap_or(usize::MAX)
+}
+
+/// Check that `prefix` can be used in Kakoune option, face and highlighter names.
+pub fn validate_prefix(prefix: &str) -> Result<(), String> {
+  if prefix
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+  {
+    Ok(())
+  } else {
+    Err(format!(
+      "invalid prefix `{prefix}`; only ASCII letters, digits, `_` and `-` are allowed"
+    ))
+  }
+}
+
+/// Check that labels can be generated with `keyset`, describing what is wrong otherwise.
+pub fn validate_keyset(keyset: &[char]) -> Result<(), String> {
+  match keyset {
+    [] => return Err("empty keyset; pass at least two keys with --keyset".to_owned()),
+    [key] => {
+      return Err(format!(
+        "keyset '{key}' has a single key; pass at least two keys with --keyset"
+      ))
+    }
+    _ => (),
+  }
+
+  for (i, key) in keyset.iter().enumerate() {
+    if keyset[..i].contains(key) {
+      return Err(format!("keyset has duplicate key '{key}'"));
+    }
+  }
+
+  Ok(())
+}
+
+/// Quote and escape a string as a JSON string.
+fn json_str(s: &str) -> String {
+  let mut json = String::with_capacity(s.len() + 2);
+  json.push('"');
+
+  for c in s.chars() {
+    match c {
+      '"' => json.push_str("\\\""),
+      '\\' => json.push_str("\\\\"),
+      '\n' => json.push_str("\\n"),
+      c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+      c => json.push(c),
+    }
+  }
+
+  json.push('"');
+  json
+}
+
+/// Character typed with a key, as reported by Kakoune in `$kak_key`.
+pub fn key_char(key: &str) -> Option<char> {
+  match key {
+    "<space>" => Some(' '),
+    "<tab>" => Some('\t'),
+    "<lt>" => Some('<'),
+    "<gt>" => Some('>'),
+    "<plus>" => Some('+'),
+    "<minus>" => Some('-'),
+    "<semicolon>" => Some(';'),
+    "<percent>" => Some('%'),
+    _ => {
+      let mut chars = key.chars();
+      chars.next().filter(|_| chars.next().is_none())
+    }
+  }
+}
+
+/// Arguments of a configuration file applying to `filetype`.
+///
+/// There is one argument per line, without quoting; empty lines and lines starting with `#` are ignored. Lines after a
+/// `[filetype]` header only apply to that filetype, while those before any header apply to all of them.
+pub fn config_args(config: &str, filetype: Option<&str>) -> Vec<String> {
+  let mut applies = true;
+
+  config
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .filter(|line| {
+      match line
+        .strip_prefix('[')
+        .and_then(|line| line.strip_suffix(']'))
+      {
+        Some(section) => {
+          applies = Some(section) == filetype;
+          false
+        }
+        None => applies,
+      }
+    })
+    .map(str::to_owned)
+    .collect()
+}
+
+#[derive(Debug)]
+pub struct App {
+  keyset: Keyset,
+  sels: Vec<Sel>,
+  labels: Vec<String>,
+  key: Option<String>,
+  config: Config,
+  line_offset: usize,
+  cursor: Option<Pos>,
+
+  /// Characters typed so far, if in two-character search mode.
+  search: Option<String>,
+
+  /// Keys typed so far to reduce the labels.
+  typed: String,
+
+  /// Selections picked so far in multi-target mode.
+  picked: Vec<Sel>,
+
+  /// Columns the labels are displayed on, if not in bytes.
+  columns: Vec<Vec<usize>>,
+
+  /// Display widths of the text the labels are displayed on, if known.
+  widths: Vec<Vec<usize>>,
+
+  /// Count typed so far to pick a target, if any.
+  typed_count: Option<usize>,
+
+  /// Characters typed so far in type-to-narrow mode, if still narrowing.
+  narrowed: Option<String>,
+
+  /// Text of the targets, in type-to-narrow mode.
+  texts: Vec<String>,
+
+  /// Timestamp of the buffer when the key was typed, if known.
+  buffer_timestamp: Option<usize>,
+
+  /// Whether the hints are displayed again instead of reduced.
+  again: bool,
+
+  /// Buffer text, if read.
+  buffer: Option<Buffer>,
+
+  input: Input,
+
+  /// Why the request is invalid, if it is.
+  error: Option<String>,
+}
+
+impl App {
+  pub fn new(options: Options, action: Action, input: Input) -> Self {
+    let keyset = Keyset::from_options(&options);
+
+    let config = Config {
+      handle: options.handle,
+      label_at: options.label_at,
+      anchor: options.anchor,
+      pick: options.pick,
+      open: options.open,
+      origin: Vec::new(),
+      extend: options.extend,
+      add: options.add,
+      operator: options.operator,
+      multi: options.multi,
+      sticky: options.sticky,
+      no_auto_jump: options.no_auto_jump,
+      cancel_keys: options
+        .cancel_keys
+        .map(|keys| keys.split_whitespace().map(|key| key.to_owned()).collect())
+        .unwrap_or_else(|| vec!["<esc>".to_owned()]),
+      narrow: options.narrow,
+      count: options.count,
+      cancel_on_unknown: options.cancel_on_unknown,
+      info: options.info,
+      then: options.then,
+      prefix: options.prefix.unwrap_or_else(|| "hop".to_owned()),
+      timestamp: options.timestamp,
+      repeat: options.repeat,
+      dim: options.dim,
+      highlighter: options.highlighter.unwrap_or_default(),
+      style: options.style.unwrap_or_default(),
+      column_unit: options.column_unit.unwrap_or_default(),
+      overflow: options.overflow,
+      ignore_case: options.ignore_case,
+      avoid_conflicts: options.avoid_conflicts,
+      semantic: options.semantic,
+      label_len: options.label_len,
+      stable: options.stable,
+      order: options.order,
+      seed: options.seed,
+      group_by_line: options.group_by_line,
+      keyset: keyset.clone(),
+      relabel: options.relabel,
+      max_depth: options.max_depth,
+      max_targets: options.max_targets,
+      over_capacity: options.over_capacity.unwrap_or_default(),
+      page_key: options
+        .over_capacity
+        .filter(|over_capacity| *over_capacity == OverCapacity::Page)
+        .and_then(|_| keyset.keys.last().copied()),
+    };
+
+    let mut app = Self {
+      keyset,
+      sels: Vec::new(),
+      labels: Vec::new(),
+      key: None,
+      config,
+      line_offset: 1,
+      cursor: None,
+      search: None,
+      typed: String::new(),
+      picked: Vec::new(),
+      columns: Vec::new(),
+      widths: Vec::new(),
+      typed_count: None,
+      narrowed: None,
+      texts: Vec::new(),
+      buffer_timestamp: None,
+      again: false,
+      buffer: None,
+      input,
+      error: None,
+    };
+
+    if let Err(message) = validate_prefix(&app.config.prefix) {
+      app.error = Some(message);
+    }
+
+    app.config.origin = app.check(options.origin.as_deref().map_or(Ok(Vec::new()), parse_sels));
+
+    match action {
+      Action::Label(options) => app.start(options),
+      Action::Reduce(options) => app.resume(options),
+    }
+
+    app
+  }
+
+  /// Find the selections to hint, reading the buffer text if needed.
+  fn start(&mut self, mut options: LabelOptions) {
+    if !options.has_targets() && self.config.pick.is_none() {
+      self.error.get_or_insert_with(|| {
+        "no targets to hint; pass --sels or one of the options finding targets".to_owned()
+      });
+      return;
+    }
+
+    // TODO mode is pattern mode with a pattern of its own
+    if options.todos {
+      options.pattern = Regex::new(TODO_PATTERN).ok();
+    }
+
+    // the text is needed to find the targets, or to place and pick the labels; in two-character search mode, it is read
+    // once the characters are known
+    let labels_need_text = self.config.column_unit != ColumnUnit::Byte
+      || self.config.avoid_conflicts
+      || self.config.semantic
+      || self.config.narrow;
+
+    // the standard input carries either the selections or the text, never both
+    let sels_from_input = options.sels.as_deref() == Some("-")
+      || options.sels0
+      || options.binary
+      || options.marks
+      || options.symbols.as_deref() == Some("-");
+    if labels_need_text && sels_from_input && self.config.pick.is_none() {
+      self.error.get_or_insert_with(|| {
+        "the selections are read from the standard input, where --semantic, --avoid-conflicts, --narrow and \
+         --column-unit read the buffer text; pass the selections as arguments"
+          .to_owned()
+      });
+      return;
+    }
+    self.buffer = if self.config.pick.is_some() {
+      // the names are listed from the first line of the scratch buffer, empty ones left out
+      let names: Vec<_> = self
+        .read_input()
+        .lines()
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned())
+        .collect();
+      Some(Buffer::new(1, names.join("\n")))
+    } else {
+      (options.words
+        || options.pattern.is_some()
+        || options.grep
+        || options.links
+        || options.brackets.is_some()
+        || options.paragraphs
+        || options.sentences
+        || options.blank_lines
+        || options.indents
+        || options.numbers
+        || options.find.is_some()
+        || options.vertical
+        || (labels_need_text && !options.search2))
+        .then(|| Buffer::new(options.line_offset, self.read_input()))
+    };
+
+    // only targets found in the text can be found again; two-character search is recorded once both are known
+    if options.words {
+      let subwords = if options.subwords { " --subwords" } else { "" };
+      self.config.repeat = Some(format!("--words{subwords}"));
+    } else if let Some(pattern) = &options.pattern {
+      self.config.repeat = Some(format!("--pattern {}", sh_quote(pattern.as_str())));
+    } else if let Some(find) = &options.find {
+      let till = options
+        .till
+        .map(|till| format!(" --till {till}"))
+        .unwrap_or_default();
+      self.config.repeat = Some(format!("--find {}{till}", sh_quote(find)));
+    }
+
+    let find = match &options.find {
+      Some(key) => self.check(
+        key_char(key)
+          .map(Some)
+          .ok_or_else(|| format!("invalid key to find: {key}")),
+      ),
+      None => None,
+    };
+
+    let symbols = match options.symbols.as_deref() {
+      Some("-") => Some(self.read_input()),
+      Some(path) => Some(self.check(
+        fs::read_to_string(path).map_err(|err| format!("cannot read symbols from {path}: {err}")),
+      )),
+      None => None,
+    };
+
+    let mut sels: Vec<_> = if let (Some(_), Some(buffer)) = (self.config.pick, &self.buffer) {
+      buffer.line_starts()
+    } else if let (true, Some(buffer)) = (options.subwords, &self.buffer) {
+      buffer.subwords()
+    } else if let (true, Some(buffer)) = (options.words, &self.buffer) {
+      buffer.words()
+    } else if let (Some(pattern), Some(buffer)) = (options.pattern, &self.buffer) {
+      buffer.matches(&pattern)
+    } else if let Some(lines) = options.lines {
+      lines.line_starts()
+    } else if let (true, Some(buffer)) = (options.grep, &self.buffer) {
+      self.config.open.get_or_insert(Open::Edit);
+      buffer.grep_locations()
+    } else if let (true, Some(buffer)) = (options.links, &self.buffer) {
+      buffer.links()
+    } else if let (Some(set), Some(buffer)) = (&options.brackets, &self.buffer) {
+      buffer.brackets(set, options.land)
+    } else if let (true, Some(buffer)) = (options.paragraphs, &self.buffer) {
+      buffer.paragraphs()
+    } else if let (true, Some(buffer)) = (options.sentences, &self.buffer) {
+      buffer.sentences()
+    } else if let (true, Some(buffer)) = (options.blank_lines, &self.buffer) {
+      buffer.blank_lines()
+    } else if let (true, Some(buffer)) = (options.indents, &self.buffer) {
+      buffer.indents()
+    } else if let (true, Some(buffer)) = (options.numbers, &self.buffer) {
+      buffer.numbers()
+    } else if let (Some(c), Some(buffer)) = (find, &self.buffer) {
+      buffer.find_char(c, options.till)
+    } else if let (true, Some(cursor), Some(buffer)) =
+      (options.vertical, &options.cursor, &self.buffer)
+    {
+      buffer.column(cursor, options.tabstop)
+    } else if options.marks {
+      parse_marks(&self.read_input())
+        .into_iter()
+        .filter(|mark| {
+          options
+            .buffile
+            .iter()
+            .all(|buffile| mark.buffile == *buffile)
+        })
+        .map(|mark| mark.main)
+        .collect()
+    } else if let Some(symbols) = symbols {
+      parse_symbols(&symbols)
+        .into_iter()
+        .filter(|symbol| {
+          options
+            .symbol_prefix
+            .iter()
+            .all(|prefix| symbol.name.starts_with(prefix.as_str()))
+        })
+        .map(|symbol| Sel::new(symbol.pos.clone(), symbol.pos))
+        .collect()
+    } else {
+      let sels = match options.sels.as_deref() {
+        _ if options.sels0 => parse_sels0(&self.read_input()),
+        _ if options.binary => parse_binary(&self.read_input_bytes()).map(|(sels, _)| sels),
+        Some("-") => parse_sels(&self.read_input()),
+        sels => parse_sels(sels.unwrap_or_default()),
+      };
+      self.check(sels)
+    };
+
+    if let Some(view_range) = options.view_range {
+      // the view of other buffers is unknown
+      sels.retain(|sel| sel.buffile.is_some() || view_range.contains(sel.start.line));
+    }
+
+    if options.exclude_current {
+      let origin = &self.config.origin;
+      sels.retain(|sel| !origin.iter().any(|current| sel.overlaps(current)));
+    }
+
+    self.sels = sels;
+    self.key = options.key;
+    self.line_offset = options.line_offset;
+    self.cursor = options.cursor;
+    self.search = options
+      .search2
+      .then(|| options.search_keys.unwrap_or_default());
+  }
+
+  /// Read the labels to reduce, and how far the reduction went.
+  fn resume(&mut self, options: ReduceOptions) {
+    let split_labels =
+      |labels: &str| -> Vec<String> { labels.split_whitespace().map(|s| s.to_owned()).collect() };
+    let (sels, labels) = if options.binary {
+      match parse_binary(&self.read_input_bytes()) {
+        Ok((sels, labels)) => (Ok(sels), labels),
+        Err(err) => (Err(err), Vec::new()),
+      }
+    } else if options.sels0 || options.labels0 {
+      let input = self.read_input();
+      let entries: Vec<_> = input
+        .split('\0')
+        .filter(|entry| !entry.is_empty())
+        .collect();
+
+      match (options.sels0, options.labels0) {
+        (true, true) => (
+          parse_sel_list(entries.iter().step_by(2).copied()),
+          entries
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|&label| label.to_owned())
+            .collect(),
+        ),
+        (true, false) => (
+          parse_sel_list(entries.into_iter()),
+          split_labels(options.labels.as_deref().unwrap_or_default()),
+        ),
+        _ => (
+          parse_sels(options.sels.as_deref().unwrap_or_default()),
+          entries.into_iter().map(|label| label.to_owned()).collect(),
+        ),
+      }
+    } else {
+      let (sels, labels) = match (options.sels.as_deref(), options.labels.as_deref()) {
+        (Some("-"), Some("-")) => {
+          let input = self.read_input();
+          let (sels, labels) = input.split_once('\n').unwrap_or((&input, ""));
+          (sels.to_owned(), labels.to_owned())
+        }
+        (Some("-"), labels) => (self.read_input(), labels.unwrap_or_default().to_owned()),
+        (sels, Some("-")) => (sels.unwrap_or_default().to_owned(), self.read_input()),
+        (sels, labels) => (
+          sels.unwrap_or_default().to_owned(),
+          labels.unwrap_or_default().to_owned(),
+        ),
+      };
+      (parse_sels(&sels), split_labels(&labels))
+    };
+
+    self.sels = self.check(sels);
+    self.labels = labels;
+    self.key = options.key;
+    self.again = options.again;
+    self.typed = options.typed.unwrap_or_default();
+    self.picked = self.check(options.picked.as_deref().map_or(Ok(Vec::new()), parse_sels));
+    self.columns = self.check(
+      options
+        .columns
+        .as_deref()
+        .map_or(Ok(Vec::new()), parse_lists),
+    );
+    self.widths = self.check(
+      options
+        .widths
+        .as_deref()
+        .map_or(Ok(Vec::new()), parse_lists),
+    );
+    self.typed_count = options.typed_count;
+    self.narrowed = options.narrowed;
+    self.texts = options
+      .texts
+      .as_deref()
+      .map(parse_texts)
+      .unwrap_or_default();
+    self.buffer_timestamp = options.buffer_timestamp;
+
+    if self.sels.len() != self.labels.len() {
+      self.error.get_or_insert(format!(
+        "{} selections cannot be reduced with {} labels",
+        self.sels.len(),
+        self.labels.len()
+      ));
+    }
+  }
+
+  /// Value of `result`, keeping its error — if it is the first one of the request — to fail with it.
+  fn check<T: Default>(&mut self, result: Result<T, impl Display>) -> T {
+    result.unwrap_or_else(|err| {
+      self.error.get_or_insert(err.to_string());
+      T::default()
+    })
+  }
+
+  /// Standard input of the request as text; failing to read it fails the request.
+  fn read_input(&mut self) -> String {
+    let input = self.input.read();
+    self.check(input)
+  }
+
+  /// Standard input of the request as bytes; failing to read it fails the request.
+  fn read_input_bytes(&mut self) -> Vec<u8> {
+    let input = self.input.read_bytes();
+    self.check(input)
+  }
+
+  /// Restore the selections and labels of a previous response.
+  pub fn restore(&mut self, replace_ranges: Vec<ReplaceRange>) {
+    self.sels.clear();
+    self.labels.clear();
+    self.columns.clear();
+    self.widths.clear();
+    self.texts.clear();
+
+    for range in replace_ranges {
+      self.sels.push(range.sel);
+      self.labels.push(range.label);
+      self.columns.push(range.columns);
+      self.widths.push(range.widths);
+      self.texts.push(range.text);
+    }
+  }
+
+  pub fn process(mut self) -> Response {
+    if let Some(message) = self.error.take() {
+      return Response::Fail { message };
+    }
+
+    // labels are only generated when starting a hop, so that is where the keyset matters
+    if self.search.is_some() || (self.key.is_none() && !self.again) {
+      if let Err(message) = self.keyset.validate() {
+        return Response::Fail { message };
+      }
+    }
+
+    if let Some(search) = self.search.take() {
+      return self.search(search);
+    }
+
+    if self.again {
+      return self.again();
+    }
+
+    // if we don’t have any label / no key is set, then we are tasked to generate the labels first
+    match self.key.take() {
+      None => {
+        let sels = std::mem::take(&mut self.sels);
+        self.generate_labels(sels)
+      }
+      Some(key) => self.reduce(key),
+    }
+  }
+
+  /// Collect the two characters to search for, and hint their occurrences once both are known.
+  fn search(mut self, mut search: String) -> Response {
+    if let Some(key) = &self.key {
+      if self.config.cancel_keys.contains(key) {
+        return Response::Cleanup {
+          config: self.config,
+        };
+      }
+
+      match key_char(key) {
+        Some(c) => search.push(c),
+        None => {
+          return Response::Cleanup {
+            config: self.config,
+          }
+        }
+      }
+    }
+
+    if search.chars().count() < 2 {
+      return Response::SearchPending {
+        search,
+        keyset: self.keyset,
+        line_offset: self.line_offset,
+        cursor: self.cursor,
+        config: self.config,
+      };
+    }
+
+    self.config.repeat = Some(format!("--search2 --search-keys {}", sh_quote(&search)));
+
+    let text = match self.input.read() {
+      Ok(text) => text,
+      Err(message) => return Response::Fail { message },
+    };
+    let buffer = self.buffer.insert(Buffer::new(self.line_offset, text));
+    let sels = buffer.occurrences(&search);
+    self.generate_labels(sels)
+  }
+
+  /// Keyset of paged labels, without the key reserved to display the next page.
+  fn page_keyset(&self) -> Option<Keyset> {
+    let (_, keys) = self.keyset.keys.split_last()?;
+    Some(self.keyset.with_keys(keys.to_vec()))
+  }
+
+  /// Labels of `sels` split in pages of `page_len` selections.
+  ///
+  /// Selections of the `n`-th page, starting at 0, have their label in the page prefixed with the page key typed `n`
+  /// times, so that typing it displays the next page.
+  fn paged_labels(&self, sels: &[Sel], page_len: usize) -> Vec<String> {
+    let (Some(keyset), Some(page_key)) = (self.page_keyset(), self.config.page_key) else {
+      return Vec::new();
+    };
+    let allocator = self.allocator();
+
+    sels
+      .chunks(page_len)
+      .enumerate()
+      .flat_map(|(page, sels)| {
+        let prefix = page_key.to_string().repeat(page);
+        allocator
+          .allocate(&keyset, sels, self.buffer.as_ref())
+          .into_iter()
+          .map(move |label| format!("{prefix}{label}"))
+      })
+      .collect()
+  }
+
+  fn over_capacity_failure(&self, count: usize) -> Response {
+    Response::Fail {
+      message: format!(
+        "{count} selections cannot be hinted with labels of at most {} keys",
+        self.config.max_depth.unwrap_or_default()
+      ),
+    }
+  }
+
+  /// Strategy giving labels to selections, according to the configuration.
+  fn allocator(&self) -> Box<dyn LabelAllocator> {
+    if let Some(len) = self.config.label_len {
+      Box::new(FixedLenAllocator { len })
+    } else if self.config.group_by_line {
+      Box::new(LineAllocator)
+    } else if self.config.semantic {
+      Box::new(SemanticAllocator)
+    } else if self.config.avoid_conflicts {
+      Box::new(AvoidConflictsAllocator)
+    } else {
+      Box::new(TrieAllocator)
+    }
+  }
+
+  fn generate_labels(&self, sels: Vec<Sel>) -> Response {
+    let mut sels = dedup_sels(sels);
+
+    // waiting for a key with nothing to hint would only swallow it
+    if sels.is_empty() {
+      return Response::Fail {
+        message: "no targets to hint".to_owned(),
+      };
+    }
+
+    // shortest labels come first, so give them to the closest selections
+    match (self.config.order, &self.cursor) {
+      _ if self.config.stable => sels.sort_by_key(Sel::stable_hash),
+      (None | Some(Order::Distance), Some(cursor)) => {
+        sels.sort_by_key(|sel| sel.start.distance(cursor))
+      }
+      (Some(Order::Document | Order::Distance), _) => {
+        sels.sort_by(|a, b| (&a.buffile, &a.start).cmp(&(&b.buffile, &b.start)))
+      }
+      (Some(Order::Random), _) => shuffle(&mut sels, self.config.seed.unwrap_or_default()),
+      (None, None) => (),
+    }
+
+    // the farthest selections come last, so they are the ones dropped
+    let mut dropped = 0;
+    if let Some(max_targets) = self.config.max_targets {
+      dropped = sels.len().saturating_sub(max_targets);
+      sels.truncate(max_targets);
+    }
+
+    if let Some(len) = self.config.label_len {
+      if label_capacity(&self.keyset.keys, len) < sels.len() {
+        return Response::Fail {
+          message: format!(
+            "{} selections cannot be hinted with labels of length {len}",
+            sels.len()
+          ),
+        };
+      }
+    }
+
+    // the farthest selections come last, so they are the ones dropped or paged
+    let mut page_len = None;
+    if let Some(depth) = self.config.max_depth {
+      let capacity = Trie::capacity(&self.keyset, depth, sels.len());
+
+      if capacity < sels.len() {
+        match (self.config.over_capacity, self.page_keyset()) {
+          (OverCapacity::Drop, _) => sels.truncate(capacity),
+          (OverCapacity::Page, Some(keyset)) if Trie::capacity(&keyset, depth, 1) > 0 => {
+            page_len = Some(Trie::capacity(&keyset, depth, sels.len()));
+          }
+          _ => return self.over_capacity_failure(sels.len()),
+        }
+      }
+    }
+
+    // no need to wait for a key if there is only one place to go
+    if sels.len() == 1 && !self.config.multi && !self.config.no_auto_jump {
+      if self.config.open.is_some() {
+        let text = match &self.buffer {
+          Some(buffer) => buffer.sel_text(&sels[0]).to_owned(),
+          None => String::new(),
+        };
+
+        return Response::Open {
+          target: sels.remove(0),
+          text,
+          config: self.config.clone(),
+        };
+      }
+
+      return Response::Jump {
+        targets: sels,
+        config: self.config.clone(),
+      };
+    }
+
+    let mut labels = match page_len {
+      Some(page_len) => self.paged_labels(&sels, page_len),
+      None => self
+        .allocator()
+        .allocate(&self.keyset, &sels, self.buffer.as_ref()),
+    };
+
+    // forbidden bigrams may prevent the trie from growing enough
+    if labels.len() < sels.len() {
+      return Response::Fail {
+        message: format!(
+          "{} selections cannot be hinted; the keyset only allows {} labels",
+          sels.len(),
+          labels.len()
+        ),
+      };
+    }
+
+    // semantic and conflict-avoiding labels may still be too long; paged labels are, on purpose
+    if let (Some(depth), None) = (self.config.max_depth, page_len) {
+      let too_long = |label: &String| grapheme_count(label) > depth;
+
+      if labels.iter().any(too_long) {
+        match self.config.over_capacity {
+          OverCapacity::Fail | OverCapacity::Page => return self.over_capacity_failure(sels.len()),
+          OverCapacity::Drop => {
+            (labels, sels) = labels
+              .into_iter()
+              .zip(sels)
+              .filter(|(label, _)| !too_long(label))
+              .unzip();
+          }
+        }
+      }
+    }
+
+    let replace_ranges = labels
+      .into_iter()
+      .zip(sels)
+      .map(|(label, sel)| {
+        let columns = match &self.buffer {
+          Some(buffer) if self.config.column_unit != ColumnUnit::Byte => buffer.columns(
+            &sel,
+            self.config.column_unit,
+            grapheme_count(&label),
+            self.config.label_side(),
+            self.config.overflow,
+          ),
+          _ => Vec::new(),
+        };
+        let widths = match &self.buffer {
+          Some(buffer) if !columns.is_empty() => buffer.widths(&sel, &columns),
+          _ => Vec::new(),
+        };
+        let text = match &self.buffer {
+          Some(buffer) if self.config.pick.is_some() => buffer.text_at(&sel, usize::MAX),
+          Some(buffer) if self.config.open.is_some() => buffer.sel_text(&sel),
+          Some(buffer) if self.config.narrow => buffer.text_at(&sel, NARROW_TEXT_LEN),
+          _ => "",
+        };
+
+        ReplaceRange::new(sel, label)
+          .with_columns(columns)
+          .with_widths(widths)
+          .with_text(text)
+      })
+      .collect();
+
+    Response::LabelsGenerated {
+      replace_ranges,
+      dropped,
+      config: self.config.clone(),
+    }
+  }
+
+  /// Whether the hinted selections don’t match the buffer anymore.
+  fn is_stale(&self) -> bool {
+    matches!(
+      (self.config.timestamp, self.buffer_timestamp),
+      (Some(timestamp), Some(buffer_timestamp)) if timestamp != buffer_timestamp
+    )
+  }
+
+  /// Hints of the selections and their labels.
+  fn take_replace_ranges(&mut self) -> Vec<ReplaceRange> {
+    let columns = std::mem::take(&mut self.columns);
+    let widths = std::mem::take(&mut self.widths);
+    let texts = std::mem::take(&mut self.texts);
+
+    std::mem::take(&mut self.sels)
+      .into_iter()
+      .zip(std::mem::take(&mut self.labels))
+      .zip(columns.into_iter().chain(iter::repeat(Vec::new())))
+      .zip(widths.into_iter().chain(iter::repeat(Vec::new())))
+      .zip(texts.into_iter().chain(iter::repeat(String::new())))
+      .map(|((((sel, label), columns), widths), text)| {
+        ReplaceRange::new(sel, label)
+          .with_columns(columns)
+          .with_widths(widths)
+          .with_text(text)
+      })
+      .collect()
+  }
+
+  /// Display the hints again, as when the hop started.
+  fn again(mut self) -> Response {
+    if self.is_stale() {
+      return Response::Stale {
+        config: self.config,
+      };
+    }
+
+    Response::LabelsGenerated {
+      replace_ranges: self.take_replace_ranges(),
+      dropped: 0,
+      config: self.config,
+    }
+  }
+
+  fn reduce(mut self, key: String) -> Response {
+    let allocator = self.allocator();
+
+    // cancelling is quiet, even if the buffer changed meanwhile
+    if self.config.cancel_keys.contains(&key) {
+      return Response::Cleanup {
+        config: self.config,
+      };
+    }
+
+    if self.is_stale() {
+      return Response::Stale {
+        config: self.config,
+      };
+    }
+
+    let mut replace_ranges = self.take_replace_ranges();
+    let mut picked = self.picked;
+
+    if self.config.multi && key == "<ret>" {
+      return Response::Jump {
+        targets: picked,
+        config: self.config,
+      };
+    }
+
+    let mut typed = self.typed;
+
+    // in type-to-narrow mode, keys are matched against the text of the targets until labels are asked for
+    if let Some(mut narrowed) = self.narrowed {
+      if key == "<tab>" {
+        replace_ranges.retain(|range| range.text.starts_with(&narrowed));
+
+        return Response::Reduced {
+          replace_ranges,
+          typed,
+          typed_count: None,
+          narrowed: None,
+          picked,
+          config: self.config,
+        };
+      }
+
+      if key == "<backspace>" {
+        narrowed.pop();
+      } else if let Some(c) = key_char(&key) {
+        narrowed.push(c);
+
+        let mut remaining = replace_ranges
+          .iter()
+          .enumerate()
+          .filter(|(_, range)| range.text.starts_with(&narrowed));
+
+        match (remaining.next(), remaining.next()) {
+          (Some((i, _)), None) => return Self::resolve(self.config, replace_ranges, i),
+
+          // unknown keys are ignored, unless they are asked to cancel the hop
+          (None, _) if self.config.cancel_on_unknown => {
+            return Response::Cleanup {
+              config: self.config,
+            }
+          }
+          (None, _) => {
+            narrowed.pop();
+          }
+
+          _ => (),
+        }
+      }
+
+      return Response::Reduced {
+        replace_ranges,
+        typed,
+        typed_count: None,
+        narrowed: Some(narrowed),
+        picked,
+        config: self.config,
+      };
+    }
+
+    // digits not matching any label count the remaining targets, in document order
+    if self.config.count {
+      let mut remaining: Vec<_> = (0..replace_ranges.len())
+        .filter(|&i| replace_ranges[i].label.starts_with(&typed))
+        .collect();
+      remaining.sort_by(|&a, &b| {
+        replace_ranges[a]
+          .sel
+          .start
+          .cmp(&replace_ranges[b].sel.start)
+      });
+      let nth = |count: usize| count.checked_sub(1).and_then(|i| remaining.get(i)).copied();
+
+      let digit = key_char(&key).and_then(|c| {
+        let next = format!("{typed}{c}");
+        let is_label_key = replace_ranges
+          .iter()
+          .any(|range| range.label.starts_with(&next));
+        c.to_digit(10).filter(|_| !is_label_key)
+      });
+
+      let typed_count = match (digit, self.typed_count) {
+        (Some(digit), typed_count) => {
+          let count = typed_count.unwrap_or(0) * 10 + digit as usize;
+
+          match nth(count) {
+            // no other digit can follow
+            Some(i) if count * 10 > remaining.len() => {
+              return Self::resolve(self.config, replace_ranges, i);
+            }
+            Some(_) => Some(count),
+
+            // digits past the number of targets are ignored
+            None => typed_count,
+          }
+        }
+
+        (None, Some(count)) if key == "<ret>" => {
+          if let Some(i) = nth(count) {
+            return Self::resolve(self.config, replace_ranges, i);
+          }
+          None
+        }
+
+        (None, Some(count)) if key == "<backspace>" => Some(count / 10).filter(|&count| count > 0),
+
+        // any other key drops the count and reduces the labels
+        _ => None,
+      };
+
+      // the count is being typed or erased, so the labels are left as is
+      let counting = digit.is_some() || (self.typed_count.is_some() && key == "<backspace>");
+      if counting || typed_count.is_some() {
+        return Response::Reduced {
+          replace_ranges,
+          typed,
+          typed_count,
+          narrowed: None,
+          picked,
+          config: self.config,
+        };
+      }
+    }
+
+    // backspace undoes the last reduction step
+    if key == "<backspace>" {
+      typed.pop();
+    } else {
+      let previous_len = typed.len();
+      let known = |typed: &str| {
+        replace_ranges
+          .iter()
+          .any(|range| range.label.starts_with(typed))
+      };
+
+      match key_char(&key) {
+        Some(c) => {
+          typed.push(c);
+
+          // the lowercase key is used only if the key itself doesn’t match any label, so that uppercase labels are
+          // still compared case-sensitively
+          if self.config.ignore_case && c.is_uppercase() && !known(&typed) {
+            typed.truncate(previous_len);
+            typed.extend(c.to_lowercase());
+
+            if !known(&typed) {
+              typed.truncate(previous_len);
+              typed.push(c);
+            }
+          }
+        }
+        None => typed.push_str(&key),
+      }
+
+      // unknown keys are ignored, unless they are asked to cancel the hop
+      if !known(&typed) {
+        if self.config.cancel_on_unknown {
+          return Response::Cleanup {
+            config: self.config,
+          };
+        }
+
+        typed.truncate(previous_len);
+      }
+    }
+
+    // in multi-target mode, a fully typed label picks its selection, and we start over with the remaining ones
+    if self.config.multi {
+      if let Some(i) = replace_ranges.iter().position(|range| range.label == typed) {
+        picked.push(replace_ranges.remove(i).sel);
+        typed.clear();
+
+        if replace_ranges.is_empty() {
+          return Response::Jump {
+            targets: picked,
+            config: self.config,
+          };
+        }
+      }
+    }
+
+    // a single remaining label that was typed resolves the hop
+    if !self.config.multi && !typed.is_empty() {
+      let mut remaining = replace_ranges
+        .iter()
+        .enumerate()
+        .filter(|(_, range)| range.label.starts_with(&typed));
+
+      if let (Some((i, _)), None) = (remaining.next(), remaining.next()) {
+        return Self::resolve(self.config, replace_ranges, i);
+      }
+    }
+
+    // the remaining selections start over with labels as short as they can be
+    if self.config.relabel && !typed.is_empty() {
+      let sels: Vec<_> = replace_ranges
+        .iter()
+        .filter(|range| range.label.starts_with(&typed))
+        .map(|range| range.sel.clone())
+        .collect();
+      let labels = allocator.allocate(&self.keyset, &sels, None);
+
+      // forbidden bigrams may prevent the trie from growing enough, in which case the labels are kept
+      if labels.len() == sels.len() {
+        replace_ranges.retain(|range| range.label.starts_with(&typed));
+        for (range, label) in replace_ranges.iter_mut().zip(labels) {
+          range.relabel(label, self.config.label_side());
+        }
+        typed.clear();
+      }
+    }
+
+    Response::Reduced {
+      replace_ranges,
+      typed,
+      typed_count: None,
+      narrowed: None,
+      picked,
+      config: self.config,
+    }
+  }
+
+  /// Jump to the selection of the `i`-th range.
+  fn resolve(config: Config, mut replace_ranges: Vec<ReplaceRange>, i: usize) -> Response {
+    let ReplaceRange {
+      sel: target, text, ..
+    } = replace_ranges.remove(i);
+
+    if config.open.is_some() {
+      return Response::Open {
+        target,
+        text,
+        config,
+      };
+    }
+
+    // in sticky mode, the hop goes on with the other labels, until there are none left
+    if config.sticky && !replace_ranges.is_empty() {
+      return Response::Visit {
+        target,
+        replace_ranges,
+        config,
+      };
+    }
+
+    Response::Jump {
+      targets: vec![target],
+      config,
+    }
+  }
+}
+
+/// Configuration of a hop, forwarded to every reduction.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Config {
+  /// Handle to put the cursor on, if not the one of the targets.
+  handle: Option<Handle>,
+
+  /// Side of the selections the labels are displayed on, if not the one of `handle`.
+  label_at: Option<LabelAt>,
+
+  /// Where the cursor lands in the targets, if not selecting them whole.
+  anchor: Option<Anchor>,
+
+  /// What the names listed in pick mode are, if picking one.
+  pick: Option<Pick>,
+
+  /// How the text of the chosen target is opened, if not jumped to.
+  open: Option<Open>,
+
+  /// Selections the hop started from, the first one being the main selection.
+  origin: Vec<Sel>,
+
+  /// Whether the main selection of `origin` is extended to the target.
+  extend: bool,
+
+  /// Whether the target is added to the selections of `origin`.
+  add: bool,
+
+  /// Operator applied from the main selection of `origin` to the target, if any.
+  operator: Option<Operator>,
+
+  /// Whether several targets can be picked.
+  multi: bool,
+
+  /// Whether the hop goes on once a target is jumped to.
+  sticky: bool,
+
+  /// Whether a single target is hinted instead of jumped to.
+  no_auto_jump: bool,
+
+  /// Keys cancelling the hop.
+  cancel_keys: Vec<String>,
+
+  /// Whether keys narrow the hints down by the text of their targets before labels are typed.
+  narrow: bool,
+
+  /// Whether digits not matching any label pick a target by its rank.
+  count: bool,
+
+  /// Whether keys not matching any label cancel the hop.
+  cancel_on_unknown: bool,
+
+  /// Whether the keys still matching a label are listed in an info box.
+  info: bool,
+
+  /// Kakoune commands to run at the target.
+  then: Option<String>,
+
+  /// Prefix of the Kakoune option and highlighter used to display the hints.
+  prefix: String,
+
+  /// Timestamp of the buffer the selections were taken from, if known.
+  timestamp: Option<usize>,
+
+  /// Arguments finding the targets of the hop again, if they can be found again.
+  repeat: Option<String>,
+
+  /// Whether the text of the window is dimmed while hinting.
+  dim: bool,
+
+  /// How labels are displayed.
+  highlighter: Highlighter,
+
+  /// How labels stand out from the text.
+  style: Style,
+
+  /// Unit of the text covered by each character of a label.
+  column_unit: ColumnUnit,
+
+  /// Whether labels are displayed in full past narrower selections.
+  overflow: bool,
+
+  /// Whether keys reduce the labels whatever their case.
+  ignore_case: bool,
+
+  /// Whether labels avoid starting with the character following their selection.
+  avoid_conflicts: bool,
+
+  /// Whether labels start with the first character of their selection.
+  semantic: bool,
+
+  /// Length of every label, if fixed.
+  label_len: Option<usize>,
+
+  /// Whether labels are assigned according to the positions of the selections only.
+  stable: bool,
+
+  /// Order in which selections are given labels, if not the default one.
+  order: Option<Order>,
+
+  /// Seed of the `random` order, if any.
+  seed: Option<u64>,
+
+  /// Whether selections starting on the same line share the first keys of their labels.
+  group_by_line: bool,
+
+  /// Keyset of the labels, forwarded so that every reduction and repeated hop uses the same keys.
+  keyset: Keyset,
+
+  /// Whether the remaining selections get fresh labels after each key.
+  relabel: bool,
+
+  /// Maximum length of the labels, if any.
+  max_depth: Option<usize>,
+
+  /// What to do when there are more selections than labels of `max_depth` keys.
+  over_capacity: OverCapacity,
+
+  /// Maximum number of selections to hint, if any.
+  max_targets: Option<usize>,
+
+  /// Key reserved to display the next page of selections, when they are paged.
+  page_key: Option<char>,
+}
+
+impl Config {
+  /// Handle which side of the selections the labels are displayed on; the anchor being the start.
+  fn label_side(&self) -> Handle {
+    match self.label_at {
+      Some(LabelAt::Start) => Handle::Anchor,
+      Some(LabelAt::End) => Handle::Cursor,
+      None => self.handle.unwrap_or_default(),
+    }
+  }
+
+  /// Command line arguments to forward this configuration.
+  pub fn args(&self) -> String {
+    let mut args = String::new();
+
+    // the keyset may come from the configuration file, which only `label` reads
+    if !self.keyset.keys.is_empty() {
+      args.push_str(&format!(" {}", self.keyset.args()));
+    }
+
+    if let Some(handle) = self.handle {
+      args.push_str(&format!(" --handle {handle}"));
+    }
+
+    if let Some(label_at) = self.label_at {
+      args.push_str(&format!(" --label-at {label_at}"));
+    }
+
+    if let Some(anchor) = self.anchor {
+      args.push_str(&format!(" --anchor {anchor}"));
+    }
+
+    if let Some(pick) = self.pick {
+      args.push_str(&format!(" --pick {pick}"));
+    }
+
+    if let Some(open) = &self.open {
+      args.push_str(&format!(" --open {}", sh_quote(&open.to_string())));
+    }
+
+    if !self.origin.is_empty() {
+      let origin: Vec<_> = self.origin.iter().map(Sel::to_str).collect();
+      args.push_str(&format!(" --origin {}", sh_quote(&origin.join(" "))));
+    }
+
+    if self.extend {
+      args.push_str(" --extend");
+    }
+
+    if self.add {
+      args.push_str(" --add");
+    }
+
+    if let Some(operator) = &self.operator {
+      args.push_str(&format!(" --operator {}", sh_quote(&operator.to_string())));
+    }
+
+    if self.multi {
+      args.push_str(" --multi");
+    }
+
+    if self.sticky {
+      args.push_str(" --sticky");
+    }
+
+    if self.no_auto_jump {
+      args.push_str(" --no-auto-jump");
+    }
+
+    if self.cancel_keys != ["<esc>"] {
+      args.push_str(&format!(
+        " --cancel-keys {}",
+        sh_quote(&self.cancel_keys.join(" "))
+      ));
+    }
+
+    if self.narrow {
+      args.push_str(" --narrow");
+    }
+
+    if self.count {
+      args.push_str(" --count");
+    }
+
+    if self.cancel_on_unknown {
+      args.push_str(" --cancel-on-unknown");
+    }
+
+    if self.info {
+      args.push_str(" --info");
+    }
+
+    if let Some(then) = &self.then {
+      args.push_str(&format!(" --then {}", sh_quote(then)));
+    }
+
+    if self.prefix != "hop" {
+      args.push_str(&format!(" --prefix {}", self.prefix));
+    }
+
+    if let Some(timestamp) = self.timestamp {
+      args.push_str(&format!(" --timestamp {timestamp}"));
+    }
+
+    if let Some(repeat) = &self.repeat {
+      args.push_str(&format!(" --repeat {}", sh_quote(repeat)));
+    }
+
+    if self.dim {
+      args.push_str(" --dim");
+    }
+
+    if self.highlighter != Highlighter::Replace {
+      args.push_str(&format!(" --highlighter {}", self.highlighter));
+    }
+
+    if self.style != Style::Block {
+      args.push_str(&format!(" --style {}", self.style));
+    }
+
+    if self.column_unit != ColumnUnit::Byte {
+      args.push_str(&format!(" --column-unit {}", self.column_unit));
+    }
+
+    if self.overflow {
+      args.push_str(" --overflow");
+    }
+
+    if self.ignore_case {
+      args.push_str(" --ignore-case");
+    }
+
+    if self.avoid_conflicts {
+      args.push_str(" --avoid-conflicts");
+    }
+
+    if self.semantic {
+      args.push_str(" --semantic");
+    }
+
+    if let Some(label_len) = self.label_len {
+      args.push_str(&format!(" --label-len {label_len}"));
+    }
+
+    if self.stable {
+      args.push_str(" --stable");
+    }
+
+    if let Some(order) = self.order {
+      args.push_str(&format!(" --order {order}"));
+    }
+
+    if let Some(seed) = self.seed {
+      args.push_str(&format!(" --seed {seed}"));
+    }
+
+    if self.group_by_line {
+      args.push_str(" --group-by-line");
+    }
+
+    if self.relabel {
+      args.push_str(" --relabel");
+    }
+
+    if let Some(max_depth) = self.max_depth {
+      args.push_str(&format!(" --max-depth {max_depth}"));
+
+      if self.over_capacity != OverCapacity::Fail {
+        args.push_str(&format!(" --over-capacity {}", self.over_capacity));
+      }
+    }
+
+    if let Some(max_targets) = self.max_targets {
+      args.push_str(&format!(" --max-targets {max_targets}"));
+    }
+
+    args.trim_start().to_owned()
+  }
+}
+
+/// Why a hop is cancelled when its buffer changed; see [`Response::Stale`].
+pub const STALE_MESSAGE: &str = "the buffer changed while hopping";
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "kebab-case"))]
+pub enum Response {
+  Cleanup {
+    config: Config,
+  },
+  SearchPending {
+    search: String,
+    keyset: Keyset,
+    line_offset: usize,
+    cursor: Option<Pos>,
+    config: Config,
+  },
+  LabelsGenerated {
+    replace_ranges: Vec<ReplaceRange>,
+
+    /// Number of selections not hinted because of `max_targets`.
+    dropped: usize,
+
+    config: Config,
+  },
+  Reduced {
+    /// All the hinted ranges, with their full labels.
+    replace_ranges: Vec<ReplaceRange>,
+
+    /// Keys typed so far; only the ranges which labels start with them are still displayed.
+    typed: String,
+
+    /// Count typed so far to pick a target by its rank, if any.
+    typed_count: Option<usize>,
+
+    /// Characters typed so far in type-to-narrow mode, if still narrowing; only the ranges which text start with them
+    /// are still displayed.
+    narrowed: Option<String>,
+
+    /// Selections picked so far in multi-target mode.
+    picked: Vec<Sel>,
+
+    config: Config,
+  },
+  /// Jump to `target` in sticky mode, and keep hinting the other ranges.
+  Visit {
+    target: Sel,
+    replace_ranges: Vec<ReplaceRange>,
+    config: Config,
+  },
+  /// Open the text of `target` instead of jumping to it.
+  Open {
+    target: Sel,
+    text: String,
+    config: Config,
+  },
+  /// Jump to the targets, the first one being the main one.
+  Jump {
+    targets: Vec<Sel>,
+    config: Config,
+  },
+  /// The buffer changed since the labels were generated, so the hop is cancelled.
+  Stale {
+    config: Config,
+  },
+  /// The hop cannot start; `message` describes why.
+  Fail {
+    message: String,
+  },
+}
+
+impl Response {
+  /// Replace ranges still displayed after this response, if any.
+  pub fn replace_ranges(&self) -> Option<&[ReplaceRange]> {
+    match self {
+      Self::LabelsGenerated { replace_ranges, .. }
+      | Self::Reduced { replace_ranges, .. }
+      | Self::Visit { replace_ranges, .. } => Some(replace_ranges),
+      _ => None,
+    }
+  }
+
+  /// Write the response in `format`; `callback` is only used by the Kakoune format.
+  #[cfg_attr(not(feature = "kakoune"), allow(unused_variables))]
+  pub fn write(self, out: &mut dyn Write, callback: &Callback, format: Format) -> io::Result<()> {
+    match format {
+      #[cfg(feature = "kakoune")]
+      Format::Kakoune => self.write_kakoune(out, callback),
+      Format::Json => self.write_json(out),
+      Format::Helix => self.write_helix(out),
+    }
+  }
+
+  /// Write Helix typable commands, one per line.
+  ///
+  /// Helix can neither highlight arbitrary ranges nor wait for a key from a command, so the remaining labels are echoed
+  /// and the reduction must be driven by the caller; a jump opens the buffer of the main target at its position.
+  fn write_helix(self, out: &mut dyn Write) -> io::Result<()> {
+    // the current buffer is the one named by the `buffer_name` expansion, which only happens in double quotes
+    fn goto(out: &mut dyn Write, target: &Sel) -> io::Result<()> {
+      let Pos { line, col } = target.start;
+      match target.buffile() {
+        Some(buffile) => writeln!(
+          out,
+          "open {}",
+          helix_quote(&format!("{buffile}:{line}:{col}"))
+        ),
+        None => writeln!(out, "open \"%{{buffer_name}}:{line}:{col}\""),
+      }
+    }
+
+    fn echo_labels<'a>(
+      out: &mut dyn Write,
+      replace_ranges: impl IntoIterator<Item = &'a ReplaceRange>,
+      typed: &str,
+    ) -> io::Result<()> {
+      let labels: Vec<_> = replace_ranges
+        .into_iter()
+        .filter_map(|range| {
+          let label = range.label.strip_prefix(typed)?;
+          Some(format!(
+            "{label}={}.{}",
+            range.sel.start.line, range.sel.start.col
+          ))
+        })
+        .collect();
+
+      writeln!(out, "echo {}", helix_quote(&labels.join(" ")))
+    }
+
+    match self {
+      Self::Cleanup { .. } => Ok(()),
+
+      Self::Stale { .. } => writeln!(
+        out,
+        "echo {}",
+        helix_quote(&format!("hop-kak: {STALE_MESSAGE}"))
+      ),
+
+      Self::Fail { message } => {
+        writeln!(out, "echo {}", helix_quote(&format!("hop-kak: {message}")))
+      }
+
+      Self::SearchPending { search, .. } => {
+        writeln!(out, "echo {}", helix_quote(&format!("search: {search}")))
+      }
+
+      Self::LabelsGenerated { replace_ranges, .. } => echo_labels(out, &replace_ranges, ""),
+
+      Self::Reduced {
+        replace_ranges,
+        typed,
+        ..
+      } => echo_labels(out, &replace_ranges, &typed),
+
+      Self::Visit {
+        target,
+        replace_ranges,
+        ..
+      } => {
+        goto(out, &target)?;
+        echo_labels(out, &replace_ranges, "")
+      }
+
+      Self::Open { text, .. } => writeln!(out, "open {}", helix_quote(&text)),
+
+      Self::Jump { targets, .. } => match targets.first() {
+        Some(target) => goto(out, target),
+        None => Ok(()),
+      },
+    }
+  }
+
+  /// Write a single line JSON object describing the response.
+  fn write_json(self, out: &mut dyn Write) -> io::Result<()> {
+    fn ranges<'a>(replace_ranges: impl IntoIterator<Item = &'a ReplaceRange>) -> String {
+      let ranges: Vec<_> = replace_ranges
+        .into_iter()
+        .map(|range| {
+          format!(
+            r#"{{"sel":{},"label":{}}}"#,
+            range.sel.to_json(),
+            json_str(&range.label)
+          )
+        })
+        .collect();
+      format!("[{}]", ranges.join(","))
+    }
+
+    fn sels(sels: &[Sel]) -> String {
+      let sels: Vec<_> = sels.iter().map(Sel::to_json).collect();
+      format!("[{}]", sels.join(","))
+    }
+
+    match self {
+      Self::Cleanup { .. } => writeln!(out, r#"{{"type":"cancel"}}"#),
+
+      Self::Stale { .. } => writeln!(out, r#"{{"type":"stale"}}"#),
+
+      Self::Fail { message } => writeln!(
+        out,
+        r#"{{"type":"error","message":{}}}"#,
+        json_str(&message)
+      ),
+
+      Self::SearchPending { search, .. } => {
+        writeln!(out, r#"{{"type":"search","search":{}}}"#, json_str(&search))
+      }
+
+      Self::LabelsGenerated {
+        replace_ranges,
+        dropped,
+        ..
+      } => writeln!(
+        out,
+        r#"{{"type":"labels","ranges":{},"dropped":{dropped}}}"#,
+        ranges(&replace_ranges)
+      ),
+
+      Self::Reduced {
+        replace_ranges,
+        typed,
+        picked,
+        ..
+      } => writeln!(
+        out,
+        r#"{{"type":"reduced","typed":{},"ranges":{},"picked":{}}}"#,
+        json_str(&typed),
+        ranges(
+          replace_ranges
+            .iter()
+            .filter(|range| range.label.starts_with(&typed))
+        ),
+        sels(&picked)
+      ),
+
+      Self::Visit {
+        target,
+        replace_ranges,
+        ..
+      } => writeln!(
+        out,
+        r#"{{"type":"visit","target":{},"ranges":{}}}"#,
+        target.to_json(),
+        ranges(&replace_ranges)
+      ),
+
+      Self::Open { target, text, .. } => writeln!(
+        out,
+        r#"{{"type":"open","target":{},"text":{}}}"#,
+        target.to_json(),
+        json_str(&text)
+      ),
+
+      Self::Jump { targets, .. } => {
+        writeln!(out, r#"{{"type":"jump","targets":{}}}"#, sels(&targets))
+      }
+    }
+  }
+}
+
+/// Rendering of the responses as Kakoune commands.
+#[cfg(feature = "kakoune")]
+impl Response {
+  fn set_handle(out: &mut dyn Write, handle: Handle) -> io::Result<()> {
+    match handle {
+      Handle::Anchor => writeln!(out, "execute-keys '<a-:><a-;>'"),
+      Handle::Cursor => writeln!(out, "execute-keys <a-:>"),
+    }
+  }
+
+  /// Display the labels of the ranges starting with `typed`, without it.
+  fn display_replace_ranges<'a>(
+    out: &mut dyn Write,
+    replace_ranges: impl IntoIterator<Item = &'a ReplaceRange>,
+    typed: &str,
+    config: &Config,
+  ) -> io::Result<()> {
+    // ranges are grouped by buffer, those of the current one first; with the ranges highlighter, the text covered by
+    // the labels is listed apart, to be highlighted
+    let mut groups = vec![(None, String::new(), String::new())];
+    let prefix = &config.prefix;
+    let head_face = config.style.face(&format!("{prefix}_label_head"));
+    let tail_face = config.style.face(&format!("{prefix}_label_tail"));
+    let target_face = config.style.face(&format!("{prefix}_target"));
+
+    for range in replace_ranges {
+      let sel = &range.sel;
+      let Some(label) = range.label.strip_prefix(typed) else {
+        continue;
+      };
+      // the selections of the next pages are only displayed once the page key is typed
+      if config.page_key.is_some_and(|key| label.starts_with(key)) {
+        continue;
+      }
+      let group = match groups
+        .iter()
+        .position(|(buffile, ..)| *buffile == sel.buffile())
+      {
+        Some(group) => group,
+        None => {
+          groups.push((sel.buffile(), String::new(), String::new()));
+          groups.len() - 1
+        }
+      };
+      let (_, specs, targets) = &mut groups[group];
+      // the label of a multi-line selection is anchored at its start
+      let handle = if sel.is_multiline() {
+        Handle::Anchor
+      } else {
+        config.label_side()
+      };
+      let columns = range.columns(handle, config.overflow);
+      let widths = range.widths(columns.len() - 1);
+      let label_len = grapheme_count(label).min(widths.len());
+      let (columns, widths) = match handle {
+        Handle::Anchor => (&columns[..=label_len], &widths[..label_len]),
+        Handle::Cursor => (
+          &columns[columns.len() - label_len - 1..],
+          &widths[widths.len() - label_len..],
+        ),
+      };
+      // each range is a quoted Kakoune word, in which quotes are doubled
+      let mut graphemes =
+        label
+          .graphemes(true)
+          .take(label_len)
+          .map(|grapheme| if grapheme == "'" { "''" } else { grapheme });
+
+      if config.highlighter == Highlighter::Ranges {
+        if label_len == 0 {
+          continue;
+        }
+
+        // an empty range inserts the label, in full, before the handle instead of replacing the text
+        let handle_col = match handle {
+          Handle::Anchor => columns[0],
+          Handle::Cursor => columns[label_len - 1],
+        };
+        let _ = write!(
+          specs,
+          "'{start_line}.{start_col}+0|{{{head_face}}}",
+          start_line = sel.start.line,
+          start_col = handle_col + 1,
+        );
+
+        let graphemes = label
+          .graphemes(true)
+          .map(|grapheme| if grapheme == "'" { "''" } else { grapheme });
+        for (i, grapheme) in graphemes.enumerate() {
+          if i == 1 {
+            let _ = write!(specs, "{{{tail_face}}}");
+          }
+
+          specs.push_str(grapheme);
+        }
+
+        specs.push_str("' ");
+
+        targets.push_str(&format!(
+          "'{start_line}.{start_col}+{len}|{target_face}' ",
+          start_line = sel.start.line,
+          start_col = columns[0] + 1,
+          len = columns[label_len] - columns[0],
+        ));
+
+        continue;
+      }
+
+      // always display the first grapheme differently
+      if let Some(head) = graphemes.next() {
+        let _ = write!(
+          specs,
+          "'{start_line}.{start_col}+{len}|{{{head_face}}}{head}' ",
+          start_line = sel.start.line,
+          start_col = columns[0] + 1,
+          len = columns[1] - columns[0],
+          head = pad(head, widths[0]),
+        );
+
+        if label_len > 1 {
+          let _ = write!(
+            specs,
+            "'{start_line}.{start_col}+{len}|{{{tail_face}}}",
+            start_line = sel.start.line,
+            start_col = columns[1] + 1,
+            len = columns[label_len] - columns[1],
+          );
+
+          for (grapheme, &width) in graphemes.zip(&widths[1..]) {
+            specs.push_str(&pad(grapheme, width));
+          }
+
+          specs.push_str("' ");
+        }
+      }
+    }
+
+    for (buffile, specs, targets) in groups {
+      let scope = if buffile.is_some() {
+        "buffer"
+      } else {
+        "window"
+      };
+      let mut commands = format!(
+        "set-option {scope} {prefix}_ranges %val{{timestamp}} {specs}\n",
+        prefix = config.prefix
+      );
+
+      if config.highlighter == Highlighter::Ranges {
+        let _ = writeln!(
+          commands,
+          "set-option {scope} {prefix}_targets %val{{timestamp}} {targets}",
+          prefix = config.prefix
+        );
+      }
+
+      // ranges are relative to the timestamp of their own buffer
+      match buffile {
+        Some(buffile) => writeln!(
+          out,
+          "evaluate-commands -buffer {} {}",
+          kak_quote(buffile),
+          kak_quote(&commands)
+        )?,
+        None => out.write_all(commands.as_bytes())?,
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Display the labels of a new hop, and wait for the first key.
+  fn display_labels(
+    out: &mut dyn Write,
+    callback: &Callback,
+    replace_ranges: &[ReplaceRange],
+    config: &Config,
+  ) -> io::Result<()> {
+    Self::display_cleanup(out, config)?;
+
+    if config.pick.is_some() {
+      Self::display_pick_list(out, replace_ranges, config)?;
+    }
+
+    // the options are only set if declared
+    writeln!(
+      out,
+      "try %{{ set-option window {prefix}_active true }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ set-option window {prefix}_modeline {modeline} }}",
+      prefix = config.prefix,
+      modeline = kak_quote(&format!("[{}]", config.prefix))
+    )?;
+
+    // dimming must come first so that labels are displayed above it
+    if config.dim {
+      writeln!(
+        out,
+        "add-highlighter window/{prefix}-dim fill {prefix}_dim",
+        prefix = config.prefix
+      )?;
+    }
+
+    // targets are highlighted below the labels inserted before them
+    if config.highlighter == Highlighter::Ranges {
+      writeln!(
+        out,
+        "add-highlighter window/{prefix}-targets ranges {prefix}_targets",
+        prefix = config.prefix
+      )?;
+    }
+
+    writeln!(
+      out,
+      "add-highlighter window/{prefix}-ranges replace-ranges {prefix}_ranges",
+      prefix = config.prefix
+    )?;
+
+    // targets in other buffers are highlighted in those, as displayed in other clients
+    let mut buffiles: Vec<_> = replace_ranges
+      .iter()
+      .filter_map(|range| range.sel.buffile())
+      .collect();
+    buffiles.sort_unstable();
+    buffiles.dedup();
+
+    for buffile in buffiles {
+      let mut commands = String::new();
+
+      if config.highlighter == Highlighter::Ranges {
+        let _ = writeln!(
+          commands,
+          "add-highlighter buffer/{prefix}-targets ranges {prefix}_targets",
+          prefix = config.prefix
+        );
+      }
+
+      let _ = write!(
+        commands,
+        "add-highlighter buffer/{prefix}-ranges replace-ranges {prefix}_ranges",
+        prefix = config.prefix
+      );
+      writeln!(
+        out,
+        "evaluate-commands -buffer {} {}",
+        kak_quote(buffile),
+        kak_quote(&commands)
+      )?;
+    }
+
+    Self::display_replace_ranges(out, replace_ranges, "", config)?;
+    Self::set_handle(out, config.handle.unwrap_or_default())?;
+    writeln!(out, "trigger-user-hook HopBegin")?;
+    Self::display_reduce_callback(
+      out,
+      callback,
+      replace_ranges,
+      &Progress::start(config),
+      config,
+    )
+  }
+
+  fn display_cleanup(out: &mut dyn Write, config: &Config) -> io::Result<()> {
+    writeln!(
+      out,
+      "try %{{ remove-highlighter window/{prefix}-ranges }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ remove-highlighter window/{prefix}-targets }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ remove-highlighter window/{prefix}-dim }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ unset-option window {prefix}_active }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ unset-option window {prefix}_modeline }}",
+      prefix = config.prefix
+    )?;
+
+    // the ranges of the window would hide those of its buffer in a later hop across buffers
+    writeln!(
+      out,
+      "try %{{ unset-option window {prefix}_ranges }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ unset-option window {prefix}_targets }}",
+      prefix = config.prefix
+    )?;
+    writeln!(
+      out,
+      "try %{{ evaluate-commands -buffer * %{{ \
+       try %{{ remove-highlighter buffer/{prefix}-ranges }}; \
+       try %{{ remove-highlighter buffer/{prefix}-targets }} }} }}",
+      prefix = config.prefix
+    )?;
+
+    if config.pick.is_some() {
+      writeln!(
+        out,
+        "try %{{ delete-buffer *{prefix}-pick* }}",
+        prefix = config.prefix
+      )?;
+    }
+
+    Ok(())
+  }
+
+  /// List the names to pick from in a scratch buffer, each one on the line of its hint.
+  fn display_pick_list(
+    out: &mut dyn Write,
+    replace_ranges: &[ReplaceRange],
+    config: &Config,
+  ) -> io::Result<()> {
+    let len = replace_ranges
+      .iter()
+      .map(|range| range.sel.start.line)
+      .max()
+      .unwrap_or_default();
+    let mut lines = vec![""; len];
+    for range in replace_ranges {
+      lines[range.sel.start.line - 1] = &range.text;
+    }
+
+    writeln!(out, "edit -scratch *{prefix}-pick*", prefix = config.prefix)?;
+    let fill = format!(
+      "set-register dquote {names}; execute-keys -draft '%R'",
+      names = kak_quote(&lines.join("\n"))
+    );
+    writeln!(
+      out,
+      "evaluate-commands -save-regs '\"' {}",
+      kak_quote(&fill)
+    )
+  }
+
+  /// Open the text of the chosen target.
+  fn display_open(out: &mut dyn Write, text: &str, open: &Open) -> io::Result<()> {
+    match open {
+      Open::Edit => match Location::parse_prefix(text) {
+        Some((location, _)) => {
+          write!(
+            out,
+            "edit -existing {} {}",
+            kak_quote(location.file),
+            location.line
+          )?;
+
+          match location.col {
+            Some(col) => writeln!(out, " {col}"),
+            None => writeln!(out),
+          }
+        }
+
+        None => writeln!(out, "edit -existing {}", kak_quote(text)),
+      },
+
+      Open::Command(command) => {
+        let script = format!("{command} {} >/dev/null 2>&1 </dev/null &", sh_quote(text));
+        writeln!(out, "nop {}", kak_sh(&script))
+      }
+    }
+  }
+
+  /// Switch to the name listed on the line of `target`.
+  fn display_pick(out: &mut dyn Write, target: &Sel, pick: Pick) -> io::Result<()> {
+    Self::display_new_sels(out, [target])?;
+    writeln!(out, "execute-keys Gl")?;
+    writeln!(out, "{} %val{{selection}}", pick.command())
+  }
+
+  /// List the keys continuing `typed` in an info box.
+  fn display_info<'a>(
+    out: &mut dyn Write,
+    remaining: impl IntoIterator<Item = &'a ReplaceRange>,
+    typed: &str,
+  ) -> io::Result<()> {
+    let lines: Vec<_> = next_keys(remaining, typed)
+      .into_iter()
+      .map(|(key, count)| {
+        let plural = if count == 1 { "" } else { "s" };
+        format!("{key}: {count} target{plural}")
+      })
+      .collect();
+
+    writeln!(
+      out,
+      "info -title {} {}",
+      kak_quote(&format!("hop {typed}")),
+      kak_quote(&lines.join("\n"))
+    )
+  }
+
+  /// Arguments passing the columns, widths and texts of the hints to `reduce`, when it needs them.
+  fn hints_args(replace_ranges: &[ReplaceRange], config: &Config) -> String {
+    let mut args = String::new();
+
+    if replace_ranges.iter().any(|r| !r.columns.is_empty()) {
+      let columns = lists_str(replace_ranges.iter().map(|r| r.columns.as_slice()));
+      args.push_str(&format!(" --columns {}", sh_quote(&columns)));
+    }
+
+    if replace_ranges.iter().any(|r| !r.widths.is_empty()) {
+      let widths = lists_str(replace_ranges.iter().map(|r| r.widths.as_slice()));
+      args.push_str(&format!(" --widths {}", sh_quote(&widths)));
+    }
+
+    if config.narrow || config.open.is_some() {
+      let texts = texts_str(replace_ranges.iter().map(|r| r.text.as_str()));
+      args.push_str(&format!(" --texts {}", sh_quote(&texts)));
+    }
+
+    args
+  }
+
+  /// Space separated selections and labels of the hints.
+  fn sels_labels(replace_ranges: &[ReplaceRange]) -> (String, String) {
+    // selections are about 16 bytes long, and labels 4
+    let mut sels = String::with_capacity(replace_ranges.len() * 16);
+    let mut labels = String::with_capacity(replace_ranges.len() * 4);
+    for (i, range) in replace_ranges.iter().enumerate() {
+      if i > 0 {
+        sels.push(' ');
+        labels.push(' ');
+      }
+
+      let _ = write!(sels, "{}", range.sel);
+      labels.push_str(&range.label);
+    }
+
+    (sels, labels)
+  }
+
+  fn display_reduce_callback(
+    out: &mut dyn Write,
+    callback: &Callback,
+    replace_ranges: &[ReplaceRange],
+    progress: &Progress<'_>,
+    config: &Config,
+  ) -> io::Result<()> {
+    let mut args = format!(
+      "reduce {} --typed {}",
+      config.args(),
+      sh_quote(progress.typed)
+    );
+
+    if let Some(typed_count) = progress.typed_count {
+      args.push_str(&format!(" --typed-count {typed_count}"));
+    }
+
+    if let Some(narrowed) = progress.narrowed {
+      args.push_str(&format!(" --narrowed {}", sh_quote(narrowed)));
+    }
+
+    // Kakoune tells the current timestamp when the key is typed
+    if config.timestamp.is_some() {
+      args.push_str(r#" --buffer-timestamp "$kak_timestamp""#);
+    }
+
+    if !progress.picked.is_empty() {
+      let picked: Vec<_> = progress.picked.iter().map(Sel::to_str).collect();
+      args.push_str(&format!(" --picked {}", sh_quote(&picked.join(" "))));
+    }
+
+    // the daemon already knows about the selections and labels
+    if let Callback::Daemon { .. } = callback {
+      return Self::display_on_key(out, callback, format!("{args} --restore"), None::<&str>);
+    }
+
+    args.push_str(&Self::hints_args(replace_ranges, config));
+    let (sels, labels) = Self::sels_labels(replace_ranges);
+
+    Self::display_on_key(
+      out,
+      callback,
+      format!("{args} --sels - --labels -"),
+      Some(format!(
+        r"printf '%s\n%s\n' {} {}",
+        sh_quote(&sels),
+        sh_quote(&labels)
+      )),
+    )
+  }
+
+  fn display_search_callback(
+    out: &mut dyn Write,
+    callback: &Callback,
+    search: &str,
+    keyset: &Keyset,
+    line_offset: usize,
+    cursor: Option<&Pos>,
+    config: &Config,
+  ) -> io::Result<()> {
+    let cursor = cursor
+      .map(|cursor| format!(" --cursor {}.{}", cursor.line, cursor.col))
+      .unwrap_or_default();
+
+    Self::display_on_key(
+      out,
+      callback,
+      format!(
+        "label {config} {keyset} --line-offset {line_offset}{cursor} --search2 --search-keys {search}",
+        config = config.args(),
+        keyset = keyset.args(),
+        search = sh_quote(search),
+      ),
+      Some(r#"printf %s "$kak_selection""#),
+    )
+  }
+
+  /// Call us back with `args` and the next key pressed by the user.
+  ///
+  /// If `input` is set, it is a shell command which output is passed on the standard input.
+  fn display_on_key(
+    out: &mut dyn Write,
+    callback: &Callback,
+    args: impl Display,
+    input: Option<impl Display>,
+  ) -> io::Result<()> {
+    let pipe = input.map(|input| format!("{input} | ")).unwrap_or_default();
+    let script = format!(
+      r#" {pipe}{bin} {args} --key "$kak_key" "#,
+      bin = callback.command()
+    );
+    let command = format!(
+      "evaluate-commands -save-regs ^ -no-hooks -- {}",
+      kak_sh(&script)
+    );
+
+    writeln!(out, "on-key {}", kak_quote(&command))
+  }
+
+  fn display_new_sels<'a>(
+    out: &mut dyn Write,
+    sels: impl IntoIterator<Item = &'a Sel>,
+  ) -> io::Result<()> {
+    write!(
+      out,
+      r#"set-register ^ "%val{{buffile}}@%val{{timestamp}}@0" "#
+    )?;
+    for sel in sels {
+      write!(out, "{} ", sel.desc())?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "execute-keys z")
+  }
+
+  /// Record how to repeat a resolved hop in the `<prefix>_repeat` option, if declared, for `hop-repeat`.
+  ///
+  /// The option holds the `label` arguments of the hop, but its origin and timestamp, which are those of the repeated
+  /// hop; e.g. `--keyset 'abc' --handle anchor --words`.
+  fn display_repeat(out: &mut dyn Write, config: &Config) -> io::Result<()> {
+    let Some(repeat) = &config.repeat else {
+      return Ok(());
+    };
+    let options = Config {
+      origin: Vec::new(),
+      timestamp: None,
+      repeat: None,
+      ..config.clone()
+    };
+    let set_repeat = format!(
+      "set-option buffer {prefix}_repeat {args}",
+      prefix = config.prefix,
+      args = kak_quote(&format!("{} {repeat}", options.args()))
+    );
+
+    writeln!(out, "try {}", kak_quote(&set_repeat))
+  }
+
+  /// Signal the end of the hop — cancelled or resolved — with the `HopEnd` user hook.
+  fn display_hop_end(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "trigger-user-hook HopEnd")
+  }
+
+  /// Jump to the targets a hop resolved to, the first one being the main one.
+  ///
+  /// The selections the hop started from, if known, are saved to the jump list first, so that `<c-o>` goes back to them.
+  fn display_jump(out: &mut dyn Write, targets: &[Sel], config: &Config) -> io::Result<()> {
+    if !config.origin.is_empty() {
+      Self::display_new_sels(out, &config.origin)?;
+      writeln!(out, "execute-keys <c-s>")?;
+    }
+
+    Self::display_targets(out, targets, config)?;
+
+    if let Some(operator) = &config.operator {
+      writeln!(out, "execute-keys {}", kak_quote(operator.keys()))?;
+
+      // yanking leaves the text as is, so the user is left where they started
+      if *operator == Operator::Yank {
+        Self::display_new_sels(out, &config.origin)?;
+      }
+    }
+
+    Self::display_hop_end(out)?;
+
+    if let Some(then) = &config.then {
+      writeln!(out, "{then}")?;
+    }
+
+    Ok(())
+  }
+
+  /// Select the targets, the first one being the main one, and signal it with the `HopJump` user hook.
+  fn display_targets(out: &mut dyn Write, targets: &[Sel], config: &Config) -> io::Result<()> {
+    let mut targets: Vec<_> = targets
+      .iter()
+      .map(|target| match config.anchor {
+        Some(anchor) => target.collapsed(anchor),
+        None => match config.handle {
+          Some(handle) => target.with_cursor_on(handle),
+          // Kakoune gave the anchor and the cursor; jump to the target the way it was given
+          None => target.clone(),
+        },
+      })
+      .collect();
+
+    // selections cannot span buffers, so only the targets in the buffer of the main one are selected
+    let buffile = targets.first().and_then(|target| target.buffile.clone());
+    targets.retain(|target| target.buffile == buffile);
+
+    // commands are quoted whole, as braces in the name of the buffer would close a %{} block
+    if let Some(buffile) = &buffile {
+      let buffile = kak_quote(buffile);
+      writeln!(
+        out,
+        "try {} catch {}",
+        kak_quote(&format!("buffer {buffile}")),
+        kak_quote(&format!("edit -existing {buffile}"))
+      )?;
+    }
+
+    // operators apply to the text from the origin to the target
+    if let (Some(main), Some(target), None) = (config.origin.first(), targets.first_mut(), &buffile)
+    {
+      if config.extend || config.operator.is_some() {
+        *target = Sel::new(main.anchor().clone(), target.cursor().clone());
+      }
+    }
+
+    // the targets come first so that the main one becomes the main selection
+    let mut sels: Vec<_> = targets.iter().collect();
+
+    if config.add && buffile.is_none() {
+      sels.extend(&config.origin);
+    }
+
+    Self::display_new_sels(out, sels)?;
+
+    if let Some(main) = targets.first() {
+      writeln!(
+        out,
+        "trigger-user-hook {}",
+        kak_quote(&format!("HopJump={}", main.to_str()))
+      )?;
+    }
+
+    Ok(())
+  }
+
+  fn write_kakoune(self, out: &mut dyn Write, callback: &Callback) -> io::Result<()> {
+    match self {
+      Self::Cleanup { config } => {
+        Self::display_cleanup(out, &config)?;
+        Self::display_hop_end(out)
+      }
+
+      Self::Stale { config } => {
+        Self::display_cleanup(out, &config)?;
+        Self::display_hop_end(out)?;
+        writeln!(
+          out,
+          "fail {}",
+          kak_quote(&format!("hop-kak: {STALE_MESSAGE}"))
+        )
+      }
+
+      Self::Fail { message } => writeln!(out, "fail {}", kak_quote(&format!("hop-kak: {message}"))),
+
+      Self::SearchPending {
+        search,
+        keyset,
+        line_offset,
+        cursor,
+        config,
+      } => Self::display_search_callback(
+        out,
+        callback,
+        &search,
+        &keyset,
+        line_offset,
+        cursor.as_ref(),
+        &config,
+      ),
+
+      Self::LabelsGenerated {
+        replace_ranges,
+        dropped,
+        config,
+      } => {
+        Self::display_labels(out, callback, &replace_ranges, &config)?;
+
+        if dropped > 0 {
+          writeln!(
+            out,
+            "echo {}",
+            kak_quote(&format!("hop-kak: {dropped} targets not hinted"))
+          )?;
+        }
+
+        // only the arguments of the hints are kept, so that hop-again displays them again with `reduce --again`
+        let (sels, labels) = Self::sels_labels(&replace_ranges);
+        let last = format!(
+          "{}{} --sels {} --labels {}",
+          config.args(),
+          Self::hints_args(&replace_ranges, &config),
+          sh_quote(&sels),
+          sh_quote(&labels)
+        );
+        let set_last = format!(
+          "set-option buffer {prefix}_last {last}",
+          prefix = config.prefix,
+          last = kak_quote(&last)
+        );
+
+        // the option is only set if declared
+        writeln!(out, "try {}", kak_quote(&set_last))
+      }
+
+      Self::Reduced {
+        replace_ranges,
+        typed,
+        typed_count,
+        narrowed,
+        picked,
+        config,
+      } => {
+        let remaining: Vec<_> = replace_ranges
+          .iter()
+          .filter(|range| range.label.starts_with(&typed))
+          .filter(|range| narrowed.iter().all(|n| range.text.starts_with(n)))
+          .collect();
+
+        Self::display_replace_ranges(out, remaining.iter().copied(), &typed, &config)?;
+
+        // in sticky mode, the last visited target stays selected
+        if !config.sticky {
+          Self::display_new_sels(out, remaining.iter().map(|range| &range.sel))?;
+          Self::set_handle(out, config.handle.unwrap_or_default())?;
+        }
+
+        if let Some(count) = typed_count {
+          writeln!(
+            out,
+            "echo {}",
+            kak_quote(&format!("hop-kak: target {count}"))
+          )?;
+        } else if let Some(narrowed) = &narrowed {
+          writeln!(out, "echo {}", kak_quote(&format!("hop-kak: {narrowed}")))?;
+        } else if config.info {
+          Self::display_info(out, remaining.iter().copied(), &typed)?;
+        }
+
+        let progress = Progress {
+          typed: &typed,
+          typed_count,
+          narrowed: narrowed.as_deref(),
+          picked: &picked,
+        };
+        Self::display_reduce_callback(out, callback, &replace_ranges, &progress, &config)
+      }
+
+      Self::Visit {
+        target,
+        replace_ranges,
+        config,
+      } => {
+        Self::display_replace_ranges(out, &replace_ranges, "", &config)?;
+        Self::display_targets(out, &[target], &config)?;
+
+        if let Some(then) = &config.then {
+          writeln!(out, "{then}")?;
+        }
+
+        Self::display_reduce_callback(
+          out,
+          callback,
+          &replace_ranges,
+          &Progress::start(&config),
+          &config,
+        )
+      }
+
+      Self::Open { text, config, .. } => {
+        Self::display_cleanup(out, &config)?;
+
+        if let Some(open) = &config.open {
+          Self::display_open(out, &text, open)?;
+        }
+
+        if let Some(then) = &config.then {
+          writeln!(out, "{then}")?;
+        }
+
+        Self::display_hop_end(out)
+      }
+
+      Self::Jump { targets, config } => {
+        // the name is read from the list before it is deleted
+        if let (Some(pick), Some(target)) = (config.pick, targets.first()) {
+          Self::display_pick(out, target, pick)?;
+          Self::display_cleanup(out, &config)?;
+          return Self::display_hop_end(out);
+        }
+
+        Self::display_cleanup(out, &config)?;
+
+        if targets.is_empty() {
+          return Self::display_hop_end(out);
+        }
+
+        Self::display_repeat(out, &config)?;
+        Self::display_jump(out, &targets, &config)
+      }
+    }
+  }
+}
+
+/// How far a hop went, forwarded to its next reduction.
+#[cfg(feature = "kakoune")]
+#[derive(Default)]
+struct Progress<'a> {
+  /// Keys typed so far to reduce the labels.
+  typed: &'a str,
+
+  /// Count typed so far to pick a target, if any.
+  typed_count: Option<usize>,
+
+  /// Characters typed so far in type-to-narrow mode, if still narrowing.
+  narrowed: Option<&'a str>,
+
+  /// Selections picked so far in multi-target mode.
+  picked: &'a [Sel],
+}
+
+#[cfg(feature = "kakoune")]
+impl Progress<'_> {
+  /// Progress of a hop no key was typed for yet.
+  fn start(config: &Config) -> Self {
+    Self {
+      narrowed: config.narrow.then_some(""),
+      ..Self::default()
+    }
+  }
+}
+
+/// How Kakoune calls us back to reduce the hints.
+///
+/// `bin` is the path to the hop-kak binary. It is never looked up here, so that the core also builds for targets
+/// without processes, such as WebAssembly.
+#[derive(Clone, Debug)]
+pub enum Callback {
+  /// Spawn a new process, passing it the whole state.
+  Process { bin: String },
+
+  /// Forward requests to the daemon of a Kakoune session, which keeps the state of each Kakoune `client` in memory.
+  Daemon {
+    bin: String,
+    session: String,
+    client: Option<String>,
+  },
+}
+
+#[cfg(feature = "kakoune")]
+impl Callback {
+  /// Shell command to call us back.
+  fn command(&self) -> String {
+    match self {
+      Self::Process { bin } => sh_quote(bin),
+      Self::Daemon {
+        bin,
+        session,
+        client,
+      } => {
+        let mut command = format!("{} --client --session {}", sh_quote(bin), sh_quote(session));
+        if let Some(client) = client {
+          command.push_str(&format!(" --kak-client {}", sh_quote(client)));
+        }
+        command
+      }
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReplaceRange {
+  sel: Sel,
+  label: String,
+
+  /// Columns the label is displayed on; see [`Buffer::columns`]. If empty, columns are bytes.
+  columns: Vec<usize>,
+
+  /// Display widths of the text between `columns`; see [`Buffer::widths`]. If empty, widths are `1`.
+  widths: Vec<usize>,
+
+  /// Text of the target, in type-to-narrow mode; see [`Buffer::text_at`].
+  text: String,
+}
+
+impl ReplaceRange {
+  pub fn new(sel: Sel, label: impl Into<String>) -> Self {
+    Self {
+      sel,
+      label: label.into(),
+      columns: Vec::new(),
+      widths: Vec::new(),
+      text: String::new(),
+    }
+  }
+
+  pub fn with_columns(mut self, columns: Vec<usize>) -> Self {
+    self.columns = columns;
+    self
+  }
+
+  pub fn with_widths(mut self, widths: Vec<usize>) -> Self {
+    self.widths = widths;
+    self
+  }
+
+  pub fn with_text(mut self, text: impl Into<String>) -> Self {
+    self.text = text.into();
+    self
+  }
+
+  pub fn sel(&self) -> &Sel {
+    &self.sel
+  }
+
+  pub fn label(&self) -> &str {
+    &self.label
+  }
+
+  /// Give a new `label` to the range, displayed on the `handle` side of the selection.
+  ///
+  /// Columns are computed from the side the label is displayed on, so those of a shorter label are the first or last
+  /// ones of the previous label.
+  fn relabel(&mut self, label: String, handle: Handle) {
+    let handle = if self.sel.is_multiline() {
+      Handle::Anchor
+    } else {
+      handle
+    };
+    let len = grapheme_count(&label);
+
+    if self.columns.len() > len + 1 {
+      match handle {
+        Handle::Anchor => {
+          self.columns.truncate(len + 1);
+          self.widths.truncate(len);
+        }
+        Handle::Cursor => {
+          self.columns.drain(..self.columns.len() - len - 1);
+          self.widths.drain(..self.widths.len().saturating_sub(len));
+        }
+      }
+    }
+
+    self.label = label;
+  }
+
+  /// Display widths of the `count` text units the label is displayed on.
+  pub fn widths(&self, count: usize) -> Cow<'_, [usize]> {
+    if self.widths.len() == count {
+      Cow::Borrowed(&self.widths)
+    } else {
+      Cow::Owned(vec![1; count])
+    }
+  }
+
+  /// Columns the label is displayed on, on the `handle` side of the selection.
+  ///
+  /// The end column of a multi-line selection is on another line, so its label is not bounded by it. If `overflow` is
+  /// set, the label is not bounded by the selection either. There are never more columns than needed by the label.
+  pub fn columns(&self, handle: Handle, overflow: bool) -> Cow<'_, [usize]> {
+    if !self.columns.is_empty() {
+      return Cow::Borrowed(&self.columns);
+    }
+
+    let label_len = grapheme_count(&self.label);
+    let start = self.sel.start.col.saturating_sub(1);
+
+    if self.sel.is_multiline() {
+      return (start..=start + label_len).collect();
+    }
+
+    let width = (self.sel.end.col + 1).saturating_sub(self.sel.start.col);
+    let width = if overflow {
+      label_len
+    } else {
+      width.min(label_len)
+    };
+
+    match handle {
+      Handle::Anchor => (start..=start + width).collect(),
+      Handle::Cursor => (self.sel.end.col.saturating_sub(width)..=self.sel.end.col).collect(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  use clap::{Args, FromArgMatches};
+  use regex::Regex;
+
+  use crate::{
+    config_args, dedup_sels, encode_binary, ends_sentence, json_str, key_char, label_capacity,
+    parse_binary, parse_marks, parse_sels, parse_sels0, parse_symbols, parse_texts, sh_quote,
+    shuffle, validate_keyset, with_uppercase, Anchor, AvoidConflictsAllocator, Buffer, ColumnUnit,
+    FixedLenAllocator, Handle, Input, Keyset, KeysetPreset, LabelAllocator, Land, LineAllocator,
+    LineRange, Location, Mark, Open, Operator, Pos, ReplaceRange, Sel, SelError, SemanticAllocator,
+    Symbol, Till, Trie, TrieAllocator,
+  };
+  #[cfg(feature = "kakoune")]
+  use crate::{kak_quote, kak_sh, next_keys, texts_str, Config, Highlighter, Response, Style};
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  use crate::{Action, App, Callback, Format, LabelOptions, Options, ReduceOptions};
+
+  #[test]
+  fn iter() {
+    let keyset = Keyset::new("abcd".chars());
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(4, &keyset);
+    let hints = trie.labels();
+    assert_eq!(hints, vec!["a", "b", "c", "d"]);
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(10, &keyset);
+    let hints = trie.labels();
+    assert_eq!(
+      hints,
+      vec!["a", "b", "ca", "cb", "cc", "cd", "da", "db", "dc", "dd"]
+    );
+  }
+
+  #[test]
+  fn buffer_lines() {
+    let buffer = Buffer::new(3, "foo\r\n\nbar\r");
+    let lines: Vec<_> = (2..7).map(|line| buffer.line(line)).collect();
+    assert_eq!(lines, [None, Some("foo"), Some(""), Some("bar\r"), None]);
+
+    // same as str::lines, with no empty line after the last line ending
+    let buffer = Buffer::new(1, "foo\n");
+    assert_eq!(buffer.line(1), Some("foo"));
+    assert_eq!(buffer.line(2), None);
+    assert_eq!(Buffer::new(1, "").line(1), None);
+  }
+
+  #[test]
+  fn words() {
+    let buffer = Buffer::new(3, "foo bar_1\n\n  (été) x\n");
+    let sels: Vec<_> = buffer.words().iter().map(|sel| sel.to_str()).collect();
+    assert_eq!(sels, vec!["3.1,3.3", "3.5,3.9", "5.4,5.7", "5.11,5.11"]);
+  }
+
+  #[test]
+  fn occurrences() {
+    let buffer = Buffer::new(1, "a.b a.\n(a.é)");
+    let sels: Vec<_> = buffer
+      .occurrences("a.")
+      .iter()
+      .map(|sel| sel.to_str())
+      .collect();
+    assert_eq!(sels, vec!["1.1,1.2", "1.5,1.6", "2.2,2.3"]);
+
+    let sels: Vec<_> = buffer
+      .occurrences(".é")
+      .iter()
+      .map(|sel| sel.to_str())
+      .collect();
+    assert_eq!(sels, vec!["2.3,2.4"]);
+  }
+
+  #[test]
+  fn columns() {
+    // é is two bytes, and e + U+0301 is a single grapheme of three bytes
+    let buffer = Buffer::new(1, "xéye\u{301}z");
+    let sel: Sel = "1.1,1.8".parse().unwrap();
+
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Byte, 3, Handle::Anchor, false),
+      [0, 1, 2, 3]
+    );
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 3, Handle::Anchor, false),
+      [0, 1, 3, 4]
+    );
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Grapheme, 2, Handle::Cursor, false),
+      [4, 7, 8]
+    );
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 2, Handle::Cursor, false),
+      [5, 7, 8]
+    );
+    assert!(buffer
+      .columns(
+        &"2.1,2.1".parse().unwrap(),
+        ColumnUnit::Char,
+        2,
+        Handle::Anchor,
+        false
+      )
+      .is_empty());
+
+    // overflowing labels go past the selection
+    let sel: Sel = "1.2,1.2".parse().unwrap();
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 3, Handle::Anchor, false),
+      [1, 3]
+    );
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 3, Handle::Anchor, true),
+      [1, 3, 4, 5]
+    );
+    let sel: Sel = "1.4,1.4".parse().unwrap();
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 2, Handle::Cursor, true),
+      [1, 3, 4]
+    );
+
+    let range = ReplaceRange::new("1.3,1.3".parse().unwrap(), "abc");
+    assert_eq!(*range.columns(Handle::Anchor, false), [2, 3]);
+    assert_eq!(*range.columns(Handle::Anchor, true), [2, 3, 4, 5]);
+    assert_eq!(*range.columns(Handle::Cursor, true), [0, 1, 2, 3]);
+
+    // labels of multi-line selections are at their start, whatever their end column
+    let buffer = Buffer::new(1, "xéy\nz");
+    let sel: Sel = "1.2,2.1".parse().unwrap();
+    assert_eq!(
+      buffer.columns(&sel, ColumnUnit::Char, 2, Handle::Cursor, false),
+      [1, 3, 4]
+    );
+    assert_eq!(
+      *ReplaceRange::new(sel, "abc").columns(Handle::Anchor, false),
+      [1, 2, 3, 4]
+    );
+  }
+
+  #[test]
+  fn reversed_sels() {
+    let sel: Sel = "1.5,1.1".parse().unwrap();
+    assert!(sel.reversed);
+    assert_eq!((sel.start.col, sel.end.col), (1, 5));
+    assert_eq!(sel.to_str(), "1.5,1.1");
+    assert_eq!(sel.with_cursor_on(Handle::Cursor).to_str(), "1.5,1.1");
+
+    let sel: Sel = "1.1,1.5".parse().unwrap();
+    assert_eq!(sel.with_cursor_on(Handle::Cursor).to_str(), "1.1,1.5");
+    assert_eq!(sel.with_cursor_on(Handle::Anchor).to_str(), "1.5,1.1");
+  }
+
+  #[test]
+  fn quoting() {
+    assert_eq!(sh_quote("it's $HOME"), r"'it'\''s $HOME'");
+    assert_eq!(sh_quote(""), "''");
+
+    #[cfg(feature = "kakoune")]
+    {
+      assert_eq!(kak_sh("echo {a}"), "%sh{echo {a}}");
+      assert_eq!(kak_sh("echo 'a}'"), "%sh(echo 'a}')");
+      assert_eq!(kak_sh("echo '}([<'"), "%sh|echo '}([<'|");
+      assert_eq!(kak_sh("echo '}(|>'"), "%sh[echo '}(|>']");
+    }
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn buffer_jump() {
+    let config = Config {
+      prefix: "hop".to_owned(),
+      ..Config::default()
+    };
+    let targets: Vec<Sel> = ["a}.rs@2.1,2.3", "1.1,1.1", "a}.rs@4.1,4.1"]
+      .iter()
+      .map(|sel| sel.parse().unwrap())
+      .collect();
+
+    let mut out = Vec::new();
+    Response::display_targets(&mut out, &targets, &config).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "try 'buffer ''a}.rs''' catch 'edit -existing ''a}.rs'''\n\
+       set-register ^ \"%val{buffile}@%val{timestamp}@0\" 2.1,2.3 4.1,4.1 \n\
+       execute-keys z\n\
+       trigger-user-hook 'HopJump=a}.rs@2.1,2.3'\n"
+    );
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn jump_orientation() {
+    let targets: Vec<Sel> = ["1.1,1.5", "2.5,2.1"]
+      .iter()
+      .map(|sel| sel.parse().unwrap())
+      .collect();
+    let jump = |handle| {
+      let config = Config {
+        handle,
+        ..Config::default()
+      };
+      let mut out = Vec::new();
+      Response::display_targets(&mut out, &targets[..1], &config).unwrap();
+      Response::display_targets(&mut out, &targets[1..], &config).unwrap();
+      String::from_utf8(out).unwrap()
+    };
+
+    let native = jump(None);
+    assert!(native.contains("HopJump=1.1,1.5'\n"));
+    assert!(native.contains("HopJump=2.5,2.1'\n"));
+    assert!(!Config::default().args().contains("--handle"));
+
+    let anchored = jump(Some(Handle::Anchor));
+    assert!(anchored.contains("HopJump=1.5,1.1'\n"));
+    assert!(anchored.contains("HopJump=2.5,2.1'\n"));
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn paged_ranges() {
+    let config = Config {
+      prefix: "hop".to_owned(),
+      page_key: Some('c'),
+      ..Config::default()
+    };
+    let ranges = [
+      ReplaceRange::new("1.1,1.1".parse().unwrap(), "a"),
+      ReplaceRange::new("2.1,2.1".parse().unwrap(), "ca"),
+      ReplaceRange::new("3.1,3.1".parse().unwrap(), "cca"),
+    ];
+    let displayed = |typed| {
+      let mut out = Vec::new();
+      Response::display_replace_ranges(&mut out, &ranges, typed, &config).unwrap();
+      String::from_utf8(out).unwrap()
+    };
+
+    assert_eq!(
+      displayed(""),
+      "set-option window hop_ranges %val{timestamp} '1.1+1|{hop_label_head}a' \n"
+    );
+    assert_eq!(
+      displayed("c"),
+      "set-option window hop_ranges %val{timestamp} '2.1+1|{hop_label_head}a' \n"
+    );
+  }
+
+  /// Run a hop with the `label` or `reduce` arguments `args`.
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  fn run<A: Args + Into<Action>>(args: &[&str]) -> Response {
+    let command = A::augment_args(Options::augment_args(
+      clap::Command::new("hop-kak")
+        .no_binary_name(true)
+        .disable_help_flag(true),
+    ));
+    let matches = command.get_matches_from(args);
+    let options = Options::from_arg_matches(&matches).unwrap();
+    let action = A::from_arg_matches(&matches).unwrap();
+    App::new(options, action.into(), Input::Forwarded(Vec::new())).process()
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn relabel() {
+    let relabel = |label_at, columns, widths| {
+      let resp = run::<ReduceOptions>(&[
+        "-k",
+        "ab",
+        "--relabel",
+        "--label-at",
+        label_at,
+        "--sels",
+        "1.1,1.3 2.1,2.3 3.1,3.3",
+        "--labels",
+        "a ba bb",
+        "--columns",
+        columns,
+        "--widths",
+        widths,
+        "--typed",
+        "",
+        "--key",
+        "b",
+      ]);
+      let ranges: Vec<_> = resp
+        .replace_ranges()
+        .unwrap()
+        .iter()
+        .map(|range| {
+          (
+            range.label.clone(),
+            range.columns.clone(),
+            range.widths.clone(),
+          )
+        })
+        .collect();
+      ranges
+    };
+
+    // the shorter labels keep the columns of their side of the selections
+    assert_eq!(
+      relabel("start", "0,1 0,1,2 0,1,2", "1 1,2 1,2"),
+      [
+        ("a".to_owned(), vec![0, 1], vec![1]),
+        ("b".to_owned(), vec![0, 1], vec![1])
+      ]
+    );
+    assert_eq!(
+      relabel("end", "2,3 1,2,3 1,2,3", "1 2,1 2,1"),
+      [
+        ("a".to_owned(), vec![2, 3], vec![1]),
+        ("b".to_owned(), vec![2, 3], vec![1])
+      ]
+    );
+
+    // the labels of the allocators reading the buffer text cannot be given again while reducing
+    for allocator in ["--semantic", "--avoid-conflicts"] {
+      let command = Options::augment_args(
+        clap::Command::new("hop-kak")
+          .no_binary_name(true)
+          .disable_help_flag(true),
+      );
+      assert!(command
+        .try_get_matches_from(["--relabel", allocator])
+        .is_err());
+    }
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn default_keyset() {
+    let labels: Vec<_> = run::<LabelOptions>(&["--sels", "1.1,1.1 2.1,2.1"])
+      .replace_ranges()
+      .unwrap()
+      .iter()
+      .map(|range| range.label.clone())
+      .collect();
+    assert_eq!(labels, ["f", "j"]);
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn cancel_stale() {
+    let reduce = |key| {
+      run::<ReduceOptions>(&[
+        "-k",
+        "ab",
+        "--sels",
+        "1.1,1.1 2.1,2.1",
+        "--labels",
+        "a b",
+        "--timestamp",
+        "3",
+        "--buffer-timestamp",
+        "4",
+        "--key",
+        key,
+      ])
+    };
+
+    // cancelling does not complain about the buffer having changed
+    assert!(matches!(reduce("<esc>"), Response::Cleanup { .. }));
+    assert!(matches!(reduce("a"), Response::Stale { .. }));
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn helix() {
+    let helix = |resp: Response| {
+      let mut out = Vec::new();
+      resp
+        .write(
+          &mut out,
+          &Callback::Process {
+            bin: "hop-kak".to_owned(),
+          },
+          Format::Helix,
+        )
+        .unwrap();
+      String::from_utf8(out).unwrap()
+    };
+
+    // a single target is jumped to right away
+    assert_eq!(
+      helix(run::<LabelOptions>(&["-k", "ab", "--sels", "2.3,2.5"])),
+      "open \"%{buffer_name}:2:3\"\n"
+    );
+    assert_eq!(
+      helix(run::<LabelOptions>(&[
+        "-k",
+        "ab",
+        "--sels",
+        "src/it's.rs@4.1,4.1"
+      ])),
+      "open 'src/it''s.rs:4:1'\n"
+    );
+    assert_eq!(
+      helix(Response::Fail {
+        message: "one\ntwo".to_owned()
+      }),
+      "echo 'hop-kak: one two'\n"
+    );
+  }
+
+  #[test]
+  fn failed_input() {
+    // an input which could not be read upfront fails the request when read
+    let mut input = Input::Failed("cannot read stdin: no".to_owned());
+    assert_eq!(input.read(), Err("cannot read stdin: no".to_owned()));
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn no_targets() {
+    // nothing to hint in an empty window
+    let resp = run::<LabelOptions>(&["-k", "ab", "--words"]);
+    assert!(
+      matches!(&resp, Response::Fail { message } if message == "no targets to hint"),
+      "{resp:?}"
+    );
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn sels_and_text_from_input() {
+    let args: [&[&str]; 4] = [
+      &["-k", "ab", "--semantic", "--sels", "-"],
+      &["-k", "ab", "--avoid-conflicts", "--sels0"],
+      &["-k", "ab", "--narrow", "--binary"],
+      &["-k", "ab", "--column-unit", "char", "--marks"],
+    ];
+    for args in args {
+      let resp = run::<LabelOptions>(args);
+      assert!(
+        matches!(&resp, Response::Fail { message } if message.contains("standard input")),
+        "{args:?}: {resp:?}"
+      );
+    }
+  }
+
+  #[cfg(all(feature = "cli", feature = "kakoune"))]
+  #[test]
+  fn hints_again() {
+    let sels = "1.1,1.3 2.1,2.3 3.1,3.2";
+    let callback = Callback::Process {
+      bin: "hop-kak".to_owned(),
+    };
+    let mut out = Vec::new();
+    run::<LabelOptions>(&["--keyset=ab", "--timestamp=7", "--sels", sels])
+      .write(&mut out, &callback, Format::Kakoune)
+      .unwrap();
+
+    // only the arguments of the hints are kept, so that they are displayed again with the timestamp of the buffer
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.ends_with(
+      "try 'set-option buffer hop_last ''--keyset ''''ab'''' --timestamp 7 \
+       --sels ''''1.1,1.3 2.1,2.3 3.1,3.2'''' --labels ''''a ba bb'''''''\n"
+    ));
+
+    let again = |buffer_timestamp| {
+      run::<ReduceOptions>(&[
+        "--again",
+        "--timestamp=7",
+        "--sels",
+        sels,
+        "--labels=a ba bb",
+        "--buffer-timestamp",
+        buffer_timestamp,
+      ])
+    };
+    let labels: Vec<_> = again("7")
+      .replace_ranges()
+      .unwrap()
+      .iter()
+      .map(|range| range.label.clone())
+      .collect();
+    assert_eq!(labels, ["a", "ba", "bb"]);
+    assert!(matches!(again("8"), Response::Stale { .. }));
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn styled_ranges() {
+    let config = Config {
+      prefix: "hop".to_owned(),
+      highlighter: Highlighter::Ranges,
+      style: Style::Underline,
+      ..Config::default()
+    };
+    let ranges = [ReplaceRange::new("1.1,1.5".parse().unwrap(), "ab")];
+
+    let mut out = Vec::new();
+    Response::display_replace_ranges(&mut out, &ranges, "", &config).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "set-option window hop_ranges %val{timestamp} '1.1+0|{+u@hop_label_head}a{+u@hop_label_tail}b' \n\
+       set-option window hop_targets %val{timestamp} '1.1+2|+u@hop_target' \n"
+    );
+    assert!(config.args().contains(" --style underline"));
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn ranges_highlighter() {
+    let config = Config {
+      prefix: "hop".to_owned(),
+      highlighter: Highlighter::Ranges,
+      ..Config::default()
+    };
+    let ranges = [
+      ReplaceRange::new("1.1,1.5".parse().unwrap(), "ab"),
+      ReplaceRange::new("2.3,2.3".parse().unwrap(), "b'"),
+    ];
+
+    let mut out = Vec::new();
+    Response::display_replace_ranges(&mut out, &ranges, "", &config).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "set-option window hop_ranges %val{timestamp} \
+       '1.1+0|{hop_label_head}a{hop_label_tail}b' '2.3+0|{hop_label_head}b{hop_label_tail}''' \n\
+       set-option window hop_targets %val{timestamp} '1.1+2|hop_target' '2.3+1|hop_target' \n"
+    );
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn pick_list() {
+    let buffer = Buffer::new(1, "*debug*\n\nsrc/it's.rs\n");
+    let sels = buffer.line_starts();
+    let descs: Vec<_> = sels.iter().map(|sel| sel.to_str()).collect();
+    assert_eq!(descs, ["1.1,1.1", "3.1,3.1"]);
+
+    let config = Config {
+      prefix: "hop".to_owned(),
+      ..Config::default()
+    };
+    let ranges: Vec<_> = sels
+      .iter()
+      .zip(["a", "b"])
+      .map(|(sel, label)| {
+        ReplaceRange::new(sel.clone(), label).with_text(buffer.text_at(sel, usize::MAX))
+      })
+      .collect();
+
+    let mut out = Vec::new();
+    Response::display_pick_list(&mut out, &ranges, &config).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "edit -scratch *hop-pick*\n\
+       evaluate-commands -save-regs '\"' 'set-register dquote ''*debug*\n\nsrc/it''''s.rs''; \
+       execute-keys -draft ''%R'''\n"
+    );
+  }
+
+  #[test]
+  fn grep_locations() {
+    assert_eq!(
+      Location::parse_prefix("src/main.rs:12:5:fn main() {"),
+      Some((
+        Location {
+          file: "src/main.rs",
+          line: 12,
+          col: Some(5)
+        },
+        16
+      ))
+    );
+    assert_eq!(
+      Location::parse_prefix("a b:3:x:4"),
+      Some((
+        Location {
+          file: "a b",
+          line: 3,
+          col: None
+        },
+        5
+      ))
+    );
+    assert_eq!(Location::parse_prefix("note: 12 matches"), None);
+    assert_eq!(Location::parse_prefix(":1:1:"), None);
+
+    let buffer = Buffer::new(1, "a.rs:1:2:x\nno match\nb.rs:10:é\n");
+    let sels = buffer.grep_locations();
+    let texts: Vec<_> = sels.iter().map(|sel| buffer.sel_text(sel)).collect();
+    assert_eq!(texts, ["a.rs:1:2", "b.rs:10"]);
+    assert_eq!(buffer.sel_text(&"3.9,3.9".parse().unwrap()), "é");
+  }
+
+  #[test]
+  fn links() {
+    let buffer = Buffer::new(
+      1,
+      "see <https://example.com/a?b=c>, and ./run.sh\n\
+       and/or src/main.rs:12: (~/notes) http:// a.b /",
+    );
+    let sels = buffer.links();
+    let texts: Vec<_> = sels.iter().map(|sel| buffer.sel_text(sel)).collect();
+    assert_eq!(
+      texts,
+      [
+        "https://example.com/a?b=c",
+        "./run.sh",
+        "src/main.rs:12",
+        "~/notes"
+      ]
+    );
+
+    assert_eq!("edit".parse(), Ok(Open::Edit));
+    assert_eq!("xdg-open".parse(), Ok(Open::Command("xdg-open".to_owned())));
+  }
+
+  #[test]
+  fn brackets() {
+    let buffer = Buffer::new(1, "f(a[0], b)\n(x) é<1>\n");
+    let cols = |sels: Vec<Sel>| -> Vec<_> { sels.iter().map(|sel| sel.to_str()).collect() };
+
+    assert_eq!(
+      cols(buffer.brackets("()", None)),
+      ["1.2,1.2", "1.10,1.10", "2.1,2.1", "2.3,2.3"]
+    );
+    assert_eq!(
+      cols(buffer.brackets("()", Some(Land::Inside))),
+      ["1.3,1.3", "1.9,1.9", "2.2,2.2"]
+    );
+    assert_eq!(
+      cols(buffer.brackets("<>", Some(Land::Outside))),
+      ["2.5,2.5", "2.10,2.10"]
+    );
+  }
+
+  #[test]
+  fn subwords() {
+    let buffer = Buffer::new(1, "HTTPServer_utf8Len __init__ é_Ö");
+    let texts: Vec<_> = buffer
+      .subwords()
+      .iter()
+      .map(|sel| buffer.sel_text(sel))
+      .collect();
+    assert_eq!(texts, ["HTTP", "Server", "utf8", "Len", "init", "é", "Ö"]);
+  }
+
+  #[test]
+  fn paragraphs() {
+    let buffer = Buffer::new(5, "first\nstill first\n  \n\n  second\n\nthird\n");
+    let sels: Vec<_> = buffer.paragraphs().iter().map(Sel::to_str).collect();
+    assert_eq!(sels, ["5.1,5.1", "9.1,9.1", "11.1,11.1"]);
+
+    let sels: Vec<_> = buffer.blank_lines().iter().map(Sel::to_str).collect();
+    assert_eq!(sels, ["7.1,7.1", "8.1,8.1", "10.1,10.1"]);
+  }
+
+  #[test]
+  fn indents() {
+    let buffer = Buffer::new(1, "a:\n  b\n\n  c\n    d\n  e\nf\ng\n");
+    let sels: Vec<_> = buffer.indents().iter().map(Sel::to_str).collect();
+    assert_eq!(
+      sels,
+      ["1.1,1.1", "2.3,2.3", "4.3,4.3", "5.5,5.5", "6.3,6.3", "7.1,7.1"]
+    );
+  }
+
+  #[test]
+  fn numbers() {
+    let buffer = Buffer::new(1, "x = -1_000.5 + a-2 * 0xFF, utf8 0b102 1.2.3 é9 3.");
+    let texts: Vec<_> = buffer
+      .numbers()
+      .iter()
+      .map(|sel| buffer.sel_text(sel))
+      .collect();
+    assert_eq!(texts, ["-1_000.5", "2", "0xFF", "0b10", "1.2", "3", "3"]);
+  }
+
+  #[test]
+  fn find_char() {
+    let buffer = Buffer::new(1, "a,b, é,\n,,\n");
+    let sels = |till| -> Vec<_> {
+      buffer
+        .find_char(',', till)
+        .iter()
+        .map(Sel::to_str)
+        .collect()
+    };
+
+    assert_eq!(
+      sels(None),
+      ["1.2,1.2", "1.4,1.4", "1.8,1.8", "2.1,2.1", "2.2,2.2"]
+    );
+    assert_eq!(
+      sels(Some(Till::Before)),
+      ["1.1,1.1", "1.3,1.3", "1.6,1.6", "2.1,2.1"]
+    );
+    assert_eq!(
+      sels(Some(Till::After)),
+      ["1.3,1.3", "1.5,1.5", "1.9,1.9", "2.2,2.2", "2.3,2.3"]
+    );
+  }
+
+  #[test]
+  fn column() {
+    let buffer = Buffer::new(3, "abcdef\n\tx\nab\n一二三\nabcd\n");
+    let sels = |col, tabstop| -> Vec<_> {
+      buffer
+        .column(&Pos { line: 3, col }, tabstop)
+        .iter()
+        .map(Sel::to_str)
+        .collect()
+    };
+
+    assert_eq!(sels(4, 8), ["4.1,4.1", "6.4,6.4", "7.4,7.4"]);
+    assert_eq!(sels(3, 2), ["4.2,4.2", "6.4,6.4", "7.3,7.3"]);
+    assert_eq!(sels(6, 8), ["4.1,4.1", "6.7,6.7"]);
+  }
+
+  #[test]
+  fn sentences() {
+    let buffer = Buffer::new(
+      1,
+      "Hi Dr. Who. Is it (really) you?! \"Yes.\" Meet\nJ. R. R. Tolkien, e.g. now.\n\nNew paragraph\nstill going",
+    );
+    let sels: Vec<_> = buffer.sentences().iter().map(Sel::to_str).collect();
+    assert_eq!(
+      sels,
+      ["1.1,1.1", "1.13,1.13", "1.34,1.34", "1.41,1.41", "4.1,4.1"]
+    );
+
+    assert!(ends_sentence("end.)"));
+    assert!(!ends_sentence("U.S."));
+    assert!(!ends_sentence("Mrs."));
+  }
+
+  #[test]
+  fn marks() {
+    let mark: Mark = "a src/my file.rs@12@1 1.1,1.4 3.5,3.2".parse().unwrap();
+    assert_eq!(mark.register, "a");
+    assert_eq!(mark.buffile, "src/my file.rs");
+    assert_eq!(mark.timestamp, 12);
+    assert_eq!(mark.main.to_str(), "3.5,3.2");
+
+    assert!("b some text".parse::<Mark>().is_err());
+    assert!("c ".parse::<Mark>().is_err());
+    assert!("d buf@1@2 1.1,1.1".parse::<Mark>().is_err());
+
+    let marks = parse_marks("^ a.rs@1@0 2.1,2.1\nb\nc b.rs@1@0 4.1,4.1\n");
+    let registers: Vec<_> = marks.iter().map(|mark| mark.register.as_str()).collect();
+    assert_eq!(registers, ["^", "c"]);
+  }
+
+  #[test]
+  fn symbols() {
+    let symbol: Symbol = "12.4\tmy symbol".parse().unwrap();
+    assert_eq!(symbol.pos.line, 12);
+    assert_eq!(symbol.pos.col, 4);
+    assert_eq!(symbol.name, "my symbol");
+
+    assert!("12.4 main".parse::<Symbol>().is_err());
+    assert!("main\t12.4".parse::<Symbol>().is_err());
+
+    let symbols = parse_symbols("1.1\tmain\n\n3\tbad\n5.2\tBuffer::new\n");
+    let names: Vec<_> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+    assert_eq!(names, ["main", "Buffer::new"]);
+  }
+
+  #[test]
+  fn nul_separated_sels() {
+    let sels = parse_sels0("my file.rs@1.1,1.4\x001.2,1.3\0").unwrap();
+    assert_eq!(sels.len(), 2);
+    assert_eq!(sels[0].buffile(), Some("my file.rs"));
+    assert_eq!(sels[1].to_str(), "1.2,1.3");
+
+    assert!(parse_sels0("1.1,1.4 1.2,1.3").is_err());
+    assert!(parse_sels0("").unwrap().is_empty());
+  }
+
+  #[test]
+  fn binary_sels() {
+    let sels = parse_sels("1.5,1.1 src/main.rs@2.1,2.3").unwrap();
+    let labels = ["a".to_owned(), "bé".to_owned()];
+
+    let bytes = encode_binary(&sels, &labels);
+    let (decoded, decoded_labels) = parse_binary(&bytes).unwrap();
+    let decoded: Vec<_> = decoded.iter().map(Sel::to_str).collect();
+    assert_eq!(decoded, ["1.5,1.1", "src/main.rs@2.1,2.3"]);
+    assert_eq!(decoded_labels, labels);
+
+    let (_, no_labels) = parse_binary(&encode_binary(&sels, &[])).unwrap();
+    assert!(no_labels.is_empty());
+    assert!(parse_binary(&bytes[..bytes.len() - 1]).is_err());
+    assert!(parse_binary(&[bytes.as_slice(), &[0]].concat()).is_err());
+  }
+
+  #[test]
+  fn window_range() {
+    let range = LineRange::from_window_range("9 0 30 80").unwrap();
+    assert!(!range.contains(9));
+    assert!(range.contains(10));
+    assert!(range.contains(39));
+    assert!(!range.contains(40));
+
+    assert!(LineRange::from_window_range("9 0").is_err());
+  }
+
+  #[test]
+  fn buffer_sels() {
+    let sel: Sel = "my@file.rs@3.9,3.2".parse().unwrap();
+    assert_eq!(sel.buffile(), Some("my@file.rs"));
+    assert_eq!(sel.desc(), "3.9,3.2");
+    assert_eq!(sel.to_str(), "my@file.rs@3.9,3.2");
+    assert_eq!(sel.collapsed(Anchor::Start).to_str(), "my@file.rs@3.2,3.2");
+    assert_ne!(
+      sel.stable_hash(),
+      "3.9,3.2".parse::<Sel>().unwrap().stable_hash()
+    );
+
+    assert!("a.rs@3.1".parse::<Sel>().is_err());
+    let sel = "3.1,3.2".parse::<Sel>().unwrap();
+    assert_eq!(sel.buffile(), None);
+    assert_eq!(sel.in_buffer("*grep*").to_str(), "*grep*@3.1,3.2");
+  }
+
+  #[test]
+  fn configured_args() {
+    let config =
+      "# defaults\n--semantic\n\n[markdown]\n--keyset\n  etisura  \n[rust]\n--max-depth\n2\n";
+    assert_eq!(config_args(config, None), ["--semantic"]);
+    assert_eq!(
+      config_args(config, Some("markdown")),
+      ["--semantic", "--keyset", "etisura"]
+    );
+    assert_eq!(
+      config_args(config, Some("rust")),
+      ["--semantic", "--max-depth", "2"]
+    );
+    assert_eq!(config_args(config, Some("python")), ["--semantic"]);
+  }
+
+  #[test]
+  fn shuffled_sels() {
+    let sels = parse_sels("1.1,1.1 2.1,2.1 3.1,3.1 4.1,4.1 5.1,5.1").unwrap();
+    let shuffled = |seed| {
+      let mut sels = sels.clone();
+      shuffle(&mut sels, seed);
+      sels.iter().map(Sel::to_str).collect::<Vec<_>>()
+    };
+
+    let mut sorted = shuffled(42);
+    assert_eq!(sorted, shuffled(42));
+    sorted.sort();
+    assert_eq!(
+      sorted,
+      ["1.1,1.1", "2.1,2.1", "3.1,3.1", "4.1,4.1", "5.1,5.1"]
+    );
+  }
+
+  #[test]
+  fn deduped_sels() {
+    let sels = parse_sels("1.1,1.3 2.1,2.1 1.5,1.1 a.rs@1.1,1.1 2.1,2.1 1.1,1.2").unwrap();
+    let sels: Vec<_> = dedup_sels(sels).iter().map(Sel::to_str).collect();
+    assert_eq!(sels, ["1.5,1.1", "2.1,2.1", "a.rs@1.1,1.1"]);
+  }
+
+  #[test]
+  fn overlapping_sels() {
+    let sel: Sel = "2.5,1.3".parse().unwrap();
+    let overlaps = |other: &str| sel.overlaps(&other.parse().unwrap());
+
+    assert!(overlaps("1.3,1.3"));
+    assert!(overlaps("2.5,3.1"));
+    assert!(overlaps("1.9,1.9"));
+    assert!(!overlaps("1.2,1.1"));
+    assert!(!overlaps("2.6,2.6"));
+    assert!(!overlaps("a.rs@1.3,1.3"));
+  }
+
+  #[test]
+  fn collapsed_sels() {
+    let sel: Sel = "3.9,3.2".parse().unwrap();
+    assert_eq!(sel.collapsed(Anchor::Start).to_str(), "3.2,3.2");
+    assert_eq!(sel.collapsed(Anchor::End).to_str(), "3.9,3.9");
+    assert_eq!(sel.collapsed(Anchor::Center).to_str(), "3.5,3.5");
+
+    let sel: Sel = "2.4,6.1".parse().unwrap();
+    assert_eq!(sel.collapsed(Anchor::Center).to_str(), "4.1,4.1");
+    let sel: Sel = "2.4,3.1".parse().unwrap();
+    assert_eq!(sel.collapsed(Anchor::Center).to_str(), "2.4,2.4");
+  }
+
+  #[test]
+  fn narrowing_texts() {
+    let buffer = Buffer::new(1, "foo bar\nbaz");
+    let sel: Sel = "1.5,1.7".parse().unwrap();
+    assert_eq!(buffer.text_at(&sel, 2), "ba");
+    assert_eq!(buffer.text_at(&sel, 10), "bar");
+    assert_eq!(buffer.text_at(&"3.1,3.1".parse().unwrap(), 10), "");
+
+    let texts = ["a b\\c", "", "\td"];
+    assert_eq!(parse_texts(r"a\sb\\c \e \td"), texts);
+
+    #[cfg(feature = "kakoune")]
+    assert_eq!(parse_texts(&texts_str(texts)), texts);
+  }
+
+  #[cfg(feature = "kakoune")]
+  #[test]
+  fn next_keys_counts() {
+    let sel: Sel = "1.1,1.1".parse().unwrap();
+    let ranges: Vec<_> = ["a", "ba", "bb", "cab", "cb"]
+      .into_iter()
+      .map(|label| ReplaceRange::new(sel.clone(), label))
+      .collect();
+    assert_eq!(next_keys(&ranges, ""), [('a', 1), ('b', 2), ('c', 2)]);
+    assert_eq!(next_keys(&ranges, "ca"), [('b', 1)]);
+    assert!(next_keys(&ranges, "a").is_empty());
+  }
+
+  #[test]
+  fn operators() {
+    assert_eq!("yank".parse(), Ok(Operator::Yank));
+    assert_eq!("<a-j>".parse(), Ok(Operator::Keys("<a-j>".to_owned())));
+    assert_eq!(Operator::Change.keys(), "c");
+
+    for operator in ["delete", "<a-j>"] {
+      assert_eq!(operator.parse::<Operator>().unwrap().to_string(), operator);
+    }
+  }
+
+  #[test]
+  fn invalid_sels() {
+    assert_eq!(parse_sels("1.1,1.3  2.5,2.5").map(|sels| sels.len()), Ok(2));
+    assert_eq!(
+      parse_sels("1.1,1.3 2.5").map(|sels| sels.len()),
+      Err(SelError::InList {
+        index: 2,
+        token: "2.5".to_owned(),
+        source: Box::new(SelError::Sel("2.5".to_owned())),
+      })
+    );
+    assert_eq!(
+      "1.1,1.3,1.4".parse::<Sel>().map(|sel| sel.to_str()),
+      Err(SelError::Pos("1.3,1.4".to_owned()))
+    );
+    assert_eq!(
+      "1.1.2,1.3".parse::<Sel>().map(|sel| sel.to_str()),
+      Err(SelError::Pos("1.1.2".to_owned()))
+    );
+    assert!("1.x,1.3".parse::<Sel>().is_err());
+  }
+
+  #[test]
+  fn keysets() {
+    assert!(validate_keyset(&['a', 'b']).is_ok());
+    assert!(validate_keyset(&[]).is_err());
+    assert!(validate_keyset(&['a']).is_err());
+    assert_eq!(
+      validate_keyset(&['a', 'b', 'a']),
+      Err("keyset has duplicate key 'a'".to_owned())
+    );
+    #[cfg(feature = "kakoune")]
+    assert_eq!(kak_quote("it's"), "'it''s'");
+
+    for preset in [
+      KeysetPreset::QwertyHome,
+      KeysetPreset::Colemak,
+      KeysetPreset::Dvorak,
+      KeysetPreset::Azerty,
+      KeysetPreset::Workman,
+    ] {
+      let keyset: Vec<_> = preset.keys().chars().collect();
+      assert!(validate_keyset(&keyset).is_ok(), "{preset:?}");
+      assert_eq!(keyset.len(), 26, "{preset:?}");
+    }
+  }
+
+  #[test]
+  fn uppercase_keysets() {
+    let keyset = with_uppercase("abßC1".chars().collect());
+    assert_eq!(keyset.iter().collect::<String>(), "abßC1AB");
+    assert!(validate_keyset(&keyset).is_ok());
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(6, &Keyset::new(keyset));
+    assert_eq!(trie.labels(), ["a", "b", "ß", "C", "1", "A"]);
+  }
+
+  #[test]
+  fn conflict_avoiding_labels() {
+    let buffer = Buffer::new(1, "abcab");
+    let sels = buffer.occurrences("ab");
+    let next: Vec<_> = sels.iter().map(|sel| buffer.next_char(sel)).collect();
+    assert_eq!(next, [Some('c'), None]);
+
+    let keyset = Keyset::new("cd".chars());
+    assert_eq!(
+      Trie::labels_avoiding(&keyset, &[vec!['c'], vec![]]),
+      ["d", "c"]
+    );
+    assert_eq!(
+      Trie::labels_avoiding(&keyset, &[vec!['c'], vec!['c'], vec!['d']]),
+      ["dc", "dd", "c"]
+    );
+  }
+
+  #[test]
+  fn semantic_labels() {
+    let buffer = Buffer::new(1, "foo Bar fizz 42");
+    let sels = buffer.words();
+    let firsts: Vec<_> = sels.iter().map(|sel| buffer.first_char(sel)).collect();
+    assert_eq!(firsts, [Some('f'), Some('B'), Some('f'), Some('4')]);
+
+    let keyset = Keyset::new("abfx".chars());
+    assert_eq!(
+      Trie::semantic_labels(&keyset, &firsts),
+      Some(vec![
+        "fa".to_owned(),
+        "b".to_owned(),
+        "fb".to_owned(),
+        "a".to_owned()
+      ])
+    );
+
+    // no key left for the other targets
+    let keyset = Keyset::new("bf".chars());
+    assert_eq!(Trie::semantic_labels(&keyset, &firsts), None);
+  }
+
+  #[test]
+  fn allocators() {
+    let buffer = Buffer::new(1, "foo Bar fizz 42");
+    let sels = buffer.words();
+    let keyset = Keyset::new("abfx".chars());
+
+    assert_eq!(
+      TrieAllocator.allocate(&keyset, &sels, Some(&buffer)),
+      ["a", "b", "f", "x"]
+    );
+    assert_eq!(
+      FixedLenAllocator { len: 2 }.allocate(&keyset, &sels, None),
+      ["aa", "ab", "af", "ax"]
+    );
+    assert_eq!(
+      SemanticAllocator.allocate(&keyset, &sels, Some(&buffer)),
+      ["fa", "b", "fb", "a"]
+    );
+    assert_eq!(
+      AvoidConflictsAllocator.allocate(&keyset, &sels[..2], Some(&buffer)),
+      ["a", "b"]
+    );
+
+    // without buffer, there is nothing to be smart about
+    assert_eq!(
+      SemanticAllocator.allocate(&keyset, &sels, None),
+      TrieAllocator.allocate(&keyset, &sels, None)
+    );
+    assert_eq!(
+      AvoidConflictsAllocator.allocate(&keyset, &sels, None),
+      TrieAllocator.allocate(&keyset, &sels, None)
+    );
+  }
+
+  #[test]
+  fn line_labels() {
+    let sels = parse_sels("1.1,1.1 1.5,1.5 2.1,2.1 1.9,1.9 3.1,3.1").unwrap();
+    let keyset = Keyset::new("abc".chars());
+    assert_eq!(
+      LineAllocator.allocate(&keyset, &sels, None),
+      ["aa", "ab", "b", "ac", "c"]
+    );
+
+    // the keys within a line follow the rules of the keyset
+    let keyset = keyset.with_forbidden_bigrams("aa");
+    assert_eq!(
+      LineAllocator.allocate(&keyset, &sels[..2], None),
+      ["ab", "ac"]
+    );
+
+    // no key may follow the label of the first line
+    let keyset = Keyset::new("ab".chars()).with_forbidden_bigrams("aa ab");
+    assert!(LineAllocator.allocate(&keyset, &sels, None).is_empty());
+  }
+
+  #[test]
+  fn weighted_keyset() {
+    let keyset = Keyset::parse("a:1 b:3 c");
+    assert_eq!(keyset.keys, ['a', 'b', 'c']);
+    assert_eq!(keyset.weight('b'), 3);
+    assert_eq!(keyset.weight('c'), 1);
+    assert!(keyset.validate_weights().is_ok());
+    assert_eq!(keyset.args(), "--keyset 'a:1 b:3 c:1'");
+    assert_eq!(Keyset::parse("abc").args(), "--keyset 'abc'");
+    assert!(Keyset::parse("a:x b").validate_weights().is_err());
+    assert!(Keyset::parse("a:0 b").validate_weights().is_err());
+    assert!(Keyset::parse("ab c").validate_weights().is_err());
+
+    // the heaviest key gets the children
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(5, &keyset);
+    assert_eq!(trie.labels(), ["a", "ba", "bb", "bc", "c"]);
+
+    // without weights, the last key does
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(5, &Keyset::new("abc".chars()));
+    assert_eq!(trie.labels(), ["a", "b", "ca", "cb", "cc"]);
+  }
+
+  #[test]
+  fn hand_alternation() {
+    let keyset = Keyset::new("abcd".chars()).with_layout("ab cd");
+    assert_eq!(keyset.after(None), ['a', 'b', 'c', 'd']);
+    assert_eq!(keyset.after(Some('a')), ['c', 'd', 'a', 'b']);
+    assert_eq!(keyset.after(Some('d')), ['a', 'b', 'c', 'd']);
+    assert_eq!(keyset.args(), "--keyset 'abcd' --layout 'ab cd'");
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(6, &keyset);
+    assert_eq!(trie.labels(), ["a", "b", "c", "da", "db", "dc"]);
+
+    assert_eq!(
+      Trie::fixed_labels(&keyset, 2, 6),
+      ["ac", "ad", "bc", "bd", "ca", "cb"]
+    );
+  }
+
+  #[test]
+  fn forbidden_bigrams() {
+    let keyset = Keyset::new("abc".chars()).with_forbidden_bigrams("ca cc x abc");
+    assert_eq!(keyset.forbidden_bigrams, [['c', 'a'], ['c', 'c']]);
+    assert_eq!(keyset.after(Some('c')), ['b']);
+    assert!(!keyset.allows(&['a', 'c', 'a']));
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(7, &keyset);
+    let labels = trie.labels();
+    assert_eq!(labels.len(), 7);
+    assert!(labels.iter().all(|label| {
+      let label: Vec<_> = label.chars().collect();
+      keyset.allows(&label)
+    }));
+
+    assert_eq!(
+      Trie::fixed_labels(&keyset, 2, 10),
+      ["aa", "ab", "ac", "ba", "bb", "bc", "cb"]
+    );
+
+    // c can only be followed by b, and b by anything
+    let keyset = Keyset::new("ab".chars()).with_forbidden_bigrams("aa ab");
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(3, &keyset);
+    assert_eq!(trie.labels(), ["a", "ba", "bb"]);
+  }
+
+  #[test]
+  fn many_labels() {
+    let keyset = Keyset::new("abcdefghij".chars());
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(100_000, &keyset);
+
+    let mut labels = trie.labels();
+    assert_eq!(labels.len(), 100_000);
+    assert_eq!(trie.depth(), 5);
+
+    assert!(trie.iter_labels().eq(labels.iter().cloned()));
+
+    // prefix-free, so unique too
+    labels.sort();
+    assert!(labels.windows(2).all(|pair| !pair[1].starts_with(&pair[0])));
+  }
+
+  #[test]
+  fn fixed_labels() {
+    let keyset = Keyset::new("abc".chars());
+    assert_eq!(Trie::fixed_labels(&keyset, 2, 4), ["aa", "ab", "ac", "ba"]);
+    assert_eq!(Trie::fixed_labels(&keyset, 1, 2), ["a", "b"]);
+  }
+
+  #[test]
+  fn depth() {
+    let keyset = Keyset::new("abc".chars());
+    assert_eq!(label_capacity(&keyset.keys, 2), 9);
+    assert_eq!(label_capacity(&keyset.keys, usize::MAX), usize::MAX);
+
+    assert_eq!(Trie::capacity(&keyset, 1, 10), 3);
+    assert_eq!(Trie::capacity(&keyset, 2, 10), 9);
+    assert_eq!(Trie::capacity(&keyset, 2, 5), 5);
+
+    let mut trie = Trie::default();
+    trie.grow_repeatedly(9, &keyset);
+    assert_eq!(trie.depth(), 2);
+    trie.grow(&keyset);
+    assert_eq!(trie.depth(), 3);
+  }
+
+  #[test]
+  fn stable_hashes() {
+    let sels = parse_sels("1.1,1.3 2.5,2.5 4.2,5.1").unwrap();
+    let mut hashes: Vec<_> = sels.iter().map(Sel::stable_hash).collect();
+    let mut reversed: Vec<_> = sels.iter().rev().map(Sel::stable_hash).collect();
+    hashes.sort();
+    reversed.sort();
+
+    assert_eq!(hashes, reversed);
+    assert_eq!(sels[0].stable_hash(), 0x3bc3_4620_75b7_65a7);
+  }
+
+  #[test]
+  fn widths() {
+    // 漢 and 字 are three bytes and two columns wide
+    let buffer = Buffer::new(1, "a漢字b");
+    let sel: Sel = "1.1,1.8".parse().unwrap();
+    let columns = buffer.columns(&sel, ColumnUnit::Char, 3, Handle::Anchor, false);
+
+    assert_eq!(columns, [0, 1, 4, 7]);
+    assert_eq!(buffer.widths(&sel, &columns), [1, 2, 2]);
+  }
+
+  #[test]
+  fn matches() {
+    let buffer = Buffer::new(10, "foo\nbar\n\nfoo\nbaz");
+    let sels: Vec<_> = buffer
+      .matches(&Regex::new("o\nb").unwrap())
+      .iter()
+      .map(|sel| sel.to_str())
+      .collect();
+    assert_eq!(sels, vec!["10.3,11.1", "13.3,14.1"]);
+  }
+
+  #[test]
+  fn distance() {
+    let cursor = Pos { line: 10, col: 5 };
+    let mut positions = [
+      Pos { line: 1, col: 5 },
+      Pos { line: 11, col: 50 },
+      Pos { line: 10, col: 40 },
+      Pos { line: 9, col: 4 },
+    ];
+    positions.sort_by_key(|pos| pos.distance(&cursor));
+
+    let positions: Vec<_> = positions.iter().map(|pos| (pos.line, pos.col)).collect();
+    assert_eq!(positions, vec![(10, 40), (9, 4), (11, 50), (1, 5)]);
+  }
+
+  #[test]
+  fn json_strings() {
+    assert_eq!(json_str("ab"), r#""ab""#);
+    assert_eq!(json_str("a\"b\\c\nd\t"), r#""a\"b\\c\nd\u0009""#);
+  }
+
+  #[test]
+  fn key_chars() {
+    assert_eq!(key_char("a"), Some('a'));
+    assert_eq!(key_char("é"), Some('é'));
+    assert_eq!(key_char("<space>"), Some(' '));
+    assert_eq!(key_char("<ret>"), None);
+  }
+}
@@ -1,469 +1,1397 @@
 //! A small program that reads pairs of (line, column) on the standard input and writes triples of (line, column, hint)
 //! on the standard output.
 
-use std::{fmt::Display, str::FromStr};
-
-use clap::{Parser, ValueEnum};
-use unicode_segmentation::UnicodeSegmentation;
+use std::{
+  ffi::OsString,
+  fs,
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand};
+use hop_core::{
+  config_args, Action, App, Callback, Format, Input, LabelOptions, LineRange, Options, Order,
+  ReduceOptions, Response,
+};
 
 #[cfg(feature = "init")]
-const RC: &str = include_str!("../hop.kak");
+const RC: &str = include_str!("../hop.kak.in");
 
 #[derive(Debug, Parser)]
 #[clap(
   author = "Dimitri Sabadie <dimitri.sabadie@gmail.com>",
   name = "hop-kak",
   version = concat!(env!("CARGO_PKG_VERSION"), "-", env!("GIT_HEAD")),
-  about = "Hopping around in Kakoune!"
+  about = "Hopping around in Kakoune!",
+  args_override_self = true,
+  disable_help_flag = true
 )]
 struct Cli {
-  /// Initialize Kakoune.
+  #[clap(subcommand)]
+  command: Option<Command>,
+
+  /// Daemon mode.
   ///
-  /// This should be called only once, when starting a Kakoune session.
-  #[cfg(feature = "init")]
+  /// Serve the requests of the Kakoune `session` over a Unix socket, keeping the state of the hints in memory between
+  /// requests. Requests are sent with `client`.
+  #[clap(long, requires = "session")]
+  daemon: bool,
+
+  /// Client mode.
+  ///
+  /// Forward the arguments and the standard input to the daemon of the Kakoune `session`, and write back its
+  /// response.
+  #[clap(long, requires = "session")]
+  client: bool,
+
+  /// Kakoune session served by the daemon; typically `$kak_session`.
   #[clap(long)]
-  init: bool,
+  session: Option<String>,
 
-  /// Keyset to use as base for hints.
-  #[clap(short, long)]
-  keyset: Option<String>,
+  /// Stop the daemon.
+  #[clap(long, requires = "client")]
+  stop: bool,
 
-  /// Selections to act on.
-  ///
-  /// The syntax of a single selection is two pairs separated by a comma, each pair being a pair of period separated
-  /// number: `line_start.column_start,line_end.column_end`.
+  /// Kakoune client sending the request to the daemon; typically `$kak_client`.
   ///
-  /// Selections are space separated.
-  #[clap(short, long)]
-  sels: Option<String>,
+  /// The daemon keeps the hints of each client apart, so that several clients can hop at the same time.
+  #[clap(long, requires = "client")]
+  kak_client: Option<String>,
 
-  /// Labels hints to reduce.
+  /// Output format.
   ///
-  /// This is a list of labels, space separated string, to reduce. Those are zipped with `sels`.
-  #[clap(short, long)]
-  labels: Option<String>,
+  /// The `json` format describes the hints instead of driving Kakoune, so that the label engine can be used by other
+  /// tools. The `helix` format outputs Helix typable commands, one per line.
+  #[clap(long)]
+  format: Option<Format>,
 
-  /// Reduction key.
+  /// Filetype of the buffer, selecting its section of the configuration file; typically `$kak_opt_filetype`.
   ///
-  /// Key used to reduce the list of `labels`.
-  #[clap(short = 'z', long)]
-  key: Option<String>,
+  /// The configuration file is `$HOP_KAK_CONFIG`, defaulting to `hop-kak/config` in the XDG configuration directory. Its
+  /// arguments are passed to `label` before the ones of the command line, which override them.
+  #[clap(long)]
+  filetype: Option<String>,
 
-  /// Selection handle to hint.
+  /// Append the arguments, standard input, response and output of each invocation to this file, for debugging.
   ///
-  /// It’s possible to either select the anchor or the cursor.
-  #[clap(short, long)]
-  handle: Option<Handle>,
+  /// In daemon mode, the requests are logged by the daemon.
+  #[clap(long, env = "HOP_KAK_LOG")]
+  log_file: Option<PathBuf>,
+
+  /// Print help; `-h` is `--handle`.
+  #[clap(long, global = true, action = ArgAction::Help)]
+  help: Option<bool>,
 }
 
-#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd, ValueEnum)]
-enum Handle {
-  #[default]
-  Anchor,
+#[derive(Debug, Subcommand)]
+enum Command {
+  /// Initialize Kakoune.
+  ///
+  /// This should be called only once, when starting a Kakoune session.
+  #[cfg(feature = "init")]
+  Init {
+    #[clap(flatten)]
+    init: InitOptions,
+  },
+
+  /// Start a hop, hinting selections.
+  Label {
+    #[clap(flatten)]
+    options: Options,
+
+    #[clap(flatten)]
+    label: LabelOptions,
+  },
+
+  /// Reduce the labels of a hop with a key.
+  ///
+  /// You should never need to use that command; hints call it back when a key is pressed.
+  Reduce {
+    #[clap(flatten)]
+    options: Options,
+
+    #[clap(flatten)]
+    reduce: ReduceOptions,
+  },
+
+  /// Time the stages of a hop on generated selections, printing their throughput.
+  ///
+  /// Only hop-kak is measured, so that one can tell whether it or Kakoune is slow.
+  Bench {
+    /// Number of selections to hint.
+    #[clap(long, default_value_t = 10_000)]
+    targets: usize,
+
+    /// Number of runs of each stage, averaged.
+    #[clap(long, default_value_t = 10)]
+    iterations: usize,
+
+    #[clap(flatten)]
+    options: Options,
+  },
 
-  Cursor,
+  /// Print the man page of hop-kak, or of one of its subcommands, in roff.
+  #[cfg(feature = "mangen")]
+  Mangen {
+    /// Subcommand to print the man page of, as `hop-kak-<subcommand>`.
+    subcommand: Option<String>,
+  },
+
+  /// Check the setup of the Kakoune `session`, printing a report.
+  ///
+  /// The options and faces declared by `init` are looked for with the prefix of the options, `hop-kak` is looked for in
+  /// the `PATH` of Kakoune, and the keyset of the options is checked. The configuration file is read as for `label`,
+  /// which options are accepted but not checked.
+  Doctor {
+    #[clap(flatten)]
+    options: Options,
+
+    #[clap(flatten)]
+    label: LabelOptions,
+  },
 }
 
-impl Display for Handle {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    match self {
-      Handle::Anchor => f.write_str("anchor"),
-      Handle::Cursor => f.write_str("cursor"),
-    }
-  }
+/// How to tailor the Kakoune script output by `init`.
+#[cfg(feature = "init")]
+#[derive(clap::Args, Debug)]
+struct InitOptions {
+  /// Do not map the hop commands in a user mode.
+  #[clap(long)]
+  no_default_mappings: bool,
+
+  /// Name of the user mode the hop commands are mapped in.
+  #[clap(long, default_value = "hop")]
+  user_mode_name: String,
+
+  /// Whether the faces are set; pass `false` to keep those of your theme or kakrc.
+  #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+  declare_faces: bool,
+
+  /// Colors of the declared faces.
+  #[clap(long, env = "HOP_KAK_FACES", default_value = "default")]
+  faces: FacePreset,
+
+  /// Prefix of the options, faces and highlighters; pass the same `--prefix` as your hops.
+  #[clap(long, env = "HOP_KAK_PREFIX", default_value = "hop")]
+  prefix: String,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-struct Trie {
-  key: char,
-  below: Vec<Trie>,
+/// Colors of the faces declared by `init`.
+#[cfg(feature = "init")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum FacePreset {
+  /// The colors of hop.kak; labels are told apart by their green and blue backgrounds.
+  Default,
+
+  /// Yellow and blue backgrounds, told apart with deuteranopia and protanopia, and a bold head.
+  Colorblind,
+
+  /// Black on white and white on black, told apart by brightness alone.
+  HighContrast,
 }
 
-impl Default for Trie {
-  fn default() -> Self {
-    Self {
-      key: ' ', // root is ignored
-      below: Vec::default(),
+#[cfg(feature = "init")]
+impl FacePreset {
+  /// Spec of `face` in this preset, if it differs from the one of hop.kak.
+  fn spec(self, face: &str) -> Option<&'static str> {
+    match (self, face) {
+      (Self::Colorblind, "{prefix}_label_head") => Some("black,bright-yellow+bF"),
+      (Self::Colorblind, "{prefix}_label_tail") => Some("bright-white,blue+F"),
+      (Self::HighContrast, "{prefix}_label_head") => Some("black,bright-white+bF"),
+      (Self::HighContrast, "{prefix}_label_tail") => Some("bright-white,black+bF"),
+      (Self::HighContrast, "{prefix}_target") => Some("+bu"),
+      _ => None,
     }
   }
-}
 
-impl Trie {
-  fn new(key: char) -> Self {
-    Self {
-      key,
-      below: Vec::default(),
+  /// `set-face` command of hop.kak, with the spec of this preset.
+  fn set_face(self, line: &str) -> String {
+    let face = line.split_whitespace().nth(2).unwrap_or_default();
+    match self.spec(face) {
+      Some(spec) => format!("set-face global {face} {spec}"),
+      None => line.to_owned(),
     }
   }
+}
 
-  /// Grow the trie by one key.
+#[cfg(feature = "init")]
+impl InitOptions {
+  /// Content of hop.kak, rendered with these options.
   ///
-  /// Return `true` if the key was inserted in this trie.
-  fn grow(&mut self, keyset: &[char]) {
-    if self.below.len() < keyset.len() {
-      // we are not saturated, so stop here
-      let hint = keyset[self.below.len()];
-      self.below.push(Self::new(hint));
-    } else {
-      // saturated, so go down to try a better place to insert
-      let node = self
-        .below
-        .iter_mut()
-        .rfind(|node| node.below.len() < keyset.len());
-
-      if let Some(node) = node {
-        // we grow twice because we transform an old leaf into a node
-        if node.below.is_empty() {
-          node.grow(keyset);
+  /// hop.kak is rendered from hop.kak.in, in which `{prefix}` is the prefix of the options, faces, commands and hook
+  /// group, `{prefix_arg}` the `--prefix` to pass to `hop-kak label`, if any, and `{mode}` the user mode. It is made of
+  /// paragraphs separated by blank lines, so that the faces and the mappings can be left out whole.
+  fn rc(&self) -> String {
+    let paragraphs: Vec<_> = RC
+      .split("\n\n")
+      .filter_map(|paragraph| {
+        let commands: Vec<_> = paragraph
+          .lines()
+          .filter(|line| !line.starts_with('#'))
+          .collect();
+        let all = |f: fn(&str) -> bool| !commands.is_empty() && commands.iter().all(|line| f(line));
+
+        if all(|line| line.starts_with("set-face ")) {
+          return self.declare_faces.then(|| {
+            paragraph
+              .lines()
+              .map(|line| {
+                if line.starts_with("set-face ") {
+                  self.faces.set_face(line)
+                } else {
+                  line.to_owned()
+                }
+              })
+              .collect::<Vec<_>>()
+              .join("\n")
+          });
         }
 
-        node.grow(keyset);
-      } else {
-        let i = self.below.len() - 1;
-        self.below[i].grow(keyset);
-      }
+        if all(|line| line.starts_with("map global {mode} ") || line.contains("declare-user-mode"))
+        {
+          return (!self.no_default_mappings).then(|| paragraph.to_owned());
+        }
+
+        Some(paragraph.to_owned())
+      })
+      .collect();
+    let prefix_arg = match self.prefix.as_str() {
+      "hop" => String::new(),
+      prefix => format!(" --prefix {prefix}"),
+    };
+
+    paragraphs
+      .join("\n\n")
+      .replace("{prefix_arg}", &prefix_arg)
+      .replace("{prefix}", &self.prefix)
+      .replace("{mode}", &self.user_mode_name)
+  }
+}
+
+impl Command {
+  /// Options and action of the hop to run, if any.
+  fn into_hop(self) -> Option<(Options, Action)> {
+    match self {
+      #[cfg(feature = "init")]
+      Self::Init { .. } => None,
+      Self::Label { options, label } => Some((options, Action::Label(label))),
+      Self::Reduce { options, reduce } => Some((options, Action::Reduce(reduce))),
+      #[cfg(feature = "mangen")]
+      Self::Mangen { .. } => None,
+      Self::Bench { .. } | Self::Doctor { .. } => None,
     }
   }
+}
 
-  /// Grow the trie repeatedly `n` times.
-  fn grow_repeatedly(&mut self, n: usize, keyset: &[char]) {
-    for _ in 0..n {
-      self.grow(keyset);
+fn main() {
+  let args = with_config(std::env::args_os().collect());
+  let cli = match Cli::try_parse_from(args.clone()) {
+    Ok(cli) => cli,
+
+    // help and version are not errors
+    Err(err) if !err.use_stderr() => err.exit(),
+
+    Err(err) => exit_invalid_args(err),
+  };
+
+  #[cfg(unix)]
+  if let Some(session) = &cli.session {
+    if cli.daemon {
+      if let Err(err) = daemon::serve(session) {
+        eprintln!("hop-kak: cannot serve the session: {err}");
+        std::process::exit(1);
+      }
+      return;
+    }
+
+    if cli.client {
+      if let Err(err) = daemon::forward(session) {
+        eprintln!("hop-kak: cannot talk to the daemon: {err}");
+        std::process::exit(1);
+      }
+      return;
     }
   }
 
-  fn labels(&self) -> Vec<String> {
-    let mut paths = Vec::default();
+  let Some(command) = cli.command else {
+    exit_invalid_args(
+      Cli::command().error(ErrorKind::MissingSubcommand, "a subcommand is required"),
+    );
+  };
 
-    for below in &self.below {
-      below.labels_("", &mut paths);
+  #[cfg(feature = "init")]
+  if let Command::Init { init } = &command {
+    if let Err(message) = hop_core::validate_prefix(&init.prefix) {
+      exit_invalid_args(Cli::command().error(ErrorKind::ValueValidation, message));
     }
 
-    paths
+    print!("{}", init.rc());
+    return;
   }
 
-  fn labels_(&self, path: &str, paths: &mut Vec<String>) {
-    let path = format!("{path}{}", self.key);
+  #[cfg(feature = "mangen")]
+  if let Command::Mangen { subcommand } = &command {
+    let mut out = io::stdout().lock();
+    // built so that the subcommands get the global `--help` instead of a `-h` clashing with `--handle`
+    let mut cli = Cli::command();
+    cli.build();
+    let written = match subcommand {
+      None => mangen(&mut out),
+      Some(name) => match cli.find_subcommand(name) {
+        Some(subcommand) => clap_mangen::Man::new(
+          subcommand
+            .clone()
+            .name(format!("hop-kak-{}", subcommand.get_name())),
+        )
+        .render(&mut out),
+        None => exit_invalid_args(Cli::command().error(
+          ErrorKind::InvalidSubcommand,
+          format!("no subcommand `{name}` to print the man page of"),
+        )),
+      },
+    };
+    written.expect("cannot write the man page");
+    return;
+  }
 
-    if self.below.is_empty() {
-      paths.push(path);
-    } else {
-      for below in &self.below {
-        below.labels_(&path, paths);
-      }
+  if let Command::Bench {
+    targets,
+    iterations,
+    options,
+  } = command
+  {
+    let format = cli.format.unwrap_or_default();
+    if let Err(message) = bench::run(targets, iterations.max(1), &options, format) {
+      eprintln!("hop-kak: {message}");
+      std::process::exit(1);
     }
+    return;
+  }
+
+  if let Command::Doctor { options, .. } = command {
+    let Some(session) = &cli.session else {
+      exit_invalid_args(Cli::command().error(
+        ErrorKind::MissingRequiredArgument,
+        "doctor requires the Kakoune session; pass --session \"$kak_session\"",
+      ));
+    };
+
+    let healthy = doctor::run(session, &options).unwrap_or_else(|err| {
+      println!("FAIL  session {session}: {err}");
+      false
+    });
+    std::process::exit(if healthy { 0 } else { 1 });
   }
+
+  let Some((mut options, mut action)) = command.into_hop() else {
+    return;
+  };
+  with_kak_env(&mut options, &mut action, |name| std::env::var(name).ok());
+  with_seed(&mut options);
+  let format = cli.format.unwrap_or_default();
+  let callback = Callback::Process { bin: bin() };
+
+  // the standard input is only read upfront when it must be logged
+  let input = cli.log_file.as_ref().map(|_| {
+    let mut input = Vec::new();
+    io::stdin()
+      .read_to_end(&mut input)
+      .map(|_| input)
+      .map_err(|err| format!("cannot read stdin: {err}"))
+  });
+  let app_input = match &input {
+    None => Input::Stdin,
+    Some(Ok(input)) => Input::Forwarded(input.clone()),
+    Some(Err(message)) => Input::Failed(message.clone()),
+  };
+  let app = App::new(options, action, app_input);
+
+  // responses can be huge single lines, so do not flush on every write
+  let mut out = io::BufWriter::new(io::stdout().lock());
+  let resp = app.process();
+  match (&cli.log_file, &input) {
+    (Some(path), Some(input)) => Log {
+      path,
+      args: &args,
+      input: input.as_deref().unwrap_or_default(),
+    }
+    .write(resp, &mut out, &callback, format),
+    _ => resp.write(&mut out, &callback, format),
+  }
+  .and_then(|_| out.flush())
+  .expect("cannot write response");
+}
+
+/// Sections of the man page of hop-kak which cannot be generated from the command line definitions.
+#[cfg(feature = "mangen")]
+const MAN_SECTIONS: &str = r#".SH SELECTIONS
+Selections are passed to \fBlabel\fR and \fBreduce\fR with \fB\-\-sels\fR, as in the \fBselections_desc\fR value of
+Kakoune: space separated \fIanchor_line\fR.\fIanchor_column\fR,\fIcursor_line\fR.\fIcursor_column\fR descriptions,
+such as \fB1.1,1.5\fR. Lines and columns start at 1; columns count bytes, unless \fB\-\-column\-unit\fR says
+otherwise. Selections in another buffer than the current one are prefixed with its name and \fB@\fR, such as
+\fBsrc/main.rs@12.1,12.4\fR. With \fB\-\-sels \-\fR, selections are read from the standard input.
+.SH PROTOCOL
+\fBlabel\fR outputs Kakoune commands, to be evaluated with \fBevaluate\-commands %sh{ ... }\fR. They display the
+labels with the \fIprefix\fB_ranges\fR option and the \fBwindow/\fIprefix\fB\-ranges\fR highlighter, then wait for
+a key with \fBon\-key\fR and call \fBhop\-kak reduce\fR back with it, passing the selections and labels left as
+arguments. Once a single selection is left, or the hop is cancelled, the output selects the target and removes the
+highlighters. With \fB\-\-format json\fR, a single line JSON object describing the hints is output instead; with
+\fB\-\-format helix\fR, Helix typable commands, one per line.
+"#;
+
+/// Write the man page of hop-kak, generated from the command line definitions, with the selection syntax and the
+/// protocol of the generated commands.
+#[cfg(feature = "mangen")]
+fn mangen(out: &mut dyn Write) -> io::Result<()> {
+  clap_mangen::Man::new(Cli::command()).render(out)?;
+  out.write_all(MAN_SECTIONS.as_bytes())
 }
 
-/// Position in the buffer.
-#[derive(Clone, Debug)]
-struct Pos {
-  line: usize,
-  col: usize,
+/// Invocation to log to the `--log-file`.
+struct Log<'a> {
+  path: &'a Path,
+  args: &'a [OsString],
+  input: &'a [u8],
 }
 
-impl FromStr for Pos {
-  type Err = ();
+impl Log<'_> {
+  /// Write the response, and append the invocation to the log file.
+  ///
+  /// Failing to log is reported on stderr only, so that the hop goes on.
+  fn write(
+    &self,
+    resp: Response,
+    out: &mut dyn Write,
+    callback: &Callback,
+    format: Format,
+  ) -> io::Result<()> {
+    let response = format!("{resp:#?}");
+    let mut output = Vec::new();
+    resp.write(&mut output, callback, format)?;
+    out.write_all(&output)?;
+
+    let time = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |time| time.as_secs());
+    let args: Vec<_> = self.args.iter().map(|arg| arg.to_string_lossy()).collect();
+    let entry = format!(
+      "--- {time} pid {pid}\nargs: {args}\ninput:\n{input}\nresponse: {response}\noutput:\n{output}\n",
+      pid = std::process::id(),
+      args = args.join(" "),
+      input = String::from_utf8_lossy(self.input),
+      output = String::from_utf8_lossy(&output),
+    );
 
-  fn from_str(s: &str) -> Result<Self, Self::Err> {
-    let mut parts = s.split('.');
-    let line = parts.next().ok_or(())?.parse().map_err(|_| ())?;
-    let col = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+    let logged = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(self.path)
+      .and_then(|mut file| file.write_all(entry.as_bytes()));
+    if let Err(err) = logged {
+      eprintln!("cannot log to {}: {err}", self.path.display());
+    }
 
-    Ok(Pos { line, col })
+    Ok(())
   }
 }
 
-/// A selection in the buffer.
-#[derive(Clone, Debug)]
-struct Sel {
-  start: Pos,
-  end: Pos,
+/// Failure reporting invalid command line arguments to Kakoune, instead of leaving it with no response.
+fn invalid_args(err: &clap::Error) -> Response {
+  let message = err.to_string();
+  let message = message.lines().next().unwrap_or_default();
+
+  Response::Fail {
+    message: message.trim_start_matches("error: ").to_owned(),
+  }
 }
 
-impl FromStr for Sel {
-  type Err = ();
+/// Report invalid command line arguments to both the user and Kakoune, and exit.
+fn exit_invalid_args(err: clap::Error) -> ! {
+  eprint!("{err}");
+  invalid_args(&err)
+    .write(
+      &mut io::stdout(),
+      &Callback::Process { bin: bin() },
+      Format::default(),
+    )
+    .expect("cannot write response");
+  std::process::exit(err.exit_code())
+}
+
+/// Fill the options absent from the command line with what Kakoune exports to `%sh{}` blocks: `$kak_selections_desc`
+/// for the selections to hint, if no targets are given, `$kak_timestamp` and `$kak_window_range`.
+///
+/// Kakoune only exports the variables named in the block, so they still have to be mentioned, e.g. in a comment. The
+/// variables are looked up with `env`, as the daemon gets them from its client.
+fn with_kak_env(options: &mut Options, action: &mut Action, env: impl Fn(&str) -> Option<String>) {
+  let var = |name| env(name).filter(|value| !value.is_empty());
+
+  if options.timestamp.is_none() {
+    options.timestamp = var("kak_timestamp").and_then(|timestamp| timestamp.parse().ok());
+  }
+
+  let Action::Label(label) = action else {
+    return;
+  };
 
-  fn from_str(s: &str) -> Result<Self, Self::Err> {
-    let mut parts = s.split(',');
-    let start = parts.next().ok_or(())?.parse().map_err(|_| ())?;
-    let end = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+  if !label.has_targets() && options.pick.is_none() {
+    label.sels = var("kak_selections_desc");
+  }
 
-    Ok(Sel { start, end })
+  if label.view_range.is_none() {
+    label.view_range =
+      var("kak_window_range").and_then(|range| LineRange::from_window_range(&range).ok());
   }
 }
 
-impl Sel {
-  fn to_str(&self) -> String {
-    format!(
-      "{line_start}.{col_start},{line_end}.{col_end}",
-      line_start = self.start.line,
-      col_start = self.start.col,
-      line_end = self.end.line,
-      col_end = self.end.col,
-    )
+/// Seed the `random` order with the clock, unless a `--seed` is given.
+///
+/// hop-core has no clock, as it also runs where there is none, such as wasm32.
+fn with_seed(options: &mut Options) {
+  if options.order == Some(Order::Random) && options.seed.is_none() {
+    options.seed = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .ok()
+      .map(|elapsed| elapsed.as_nanos() as u64);
   }
 }
 
-#[derive(Debug)]
-struct App {
-  keyset: Vec<char>,
-  sels: Vec<Sel>,
-  labels: Vec<String>,
-  key: Option<String>,
-  handle: Handle,
+/// Variables of Kakoune read by [`with_kak_env`].
+const KAK_ENV: [&str; 3] = ["kak_selections_desc", "kak_timestamp", "kak_window_range"];
+
+/// Path of the configuration file, if any.
+fn config_path() -> Option<PathBuf> {
+  if let Some(path) = std::env::var_os("HOP_KAK_CONFIG") {
+    return Some(PathBuf::from(path));
+  }
+
+  std::env::var_os("XDG_CONFIG_HOME")
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    .map(|dir| dir.join("hop-kak").join("config"))
 }
 
-impl App {
-  fn new(cli: Cli) -> Self {
-    let keyset = cli
-      .keyset
-      .map(|keyset| keyset.chars().collect::<Vec<_>>())
-      .unwrap_or_default();
-    let sels: Vec<_> = cli
-      .sels
-      .unwrap_or_default()
-      .split_whitespace()
-      .filter_map(|sel| sel.parse::<Sel>().ok())
-      .collect();
-    let labels = cli
-      .labels
-      .map(|labels| labels.split_whitespace().map(|s| s.to_owned()).collect())
-      .unwrap_or_default();
-    let key = cli.key;
-    let handle = cli.handle.unwrap_or_default();
-
-    Self {
-      keyset,
-      sels,
-      labels,
-      key,
-      handle,
+/// Command line arguments with those of the configuration file for the `--filetype` inserted after `label` or
+/// `doctor`, so that the command line ones come last and override them.
+fn with_config(mut args: Vec<OsString>) -> Vec<OsString> {
+  let mut filetype = None;
+  let mut subcommand = None;
+  let mut i = 1;
+
+  // the subcommand is the first argument which is not a global option or its value
+  while let Some(arg) = args.get(i).and_then(|arg| arg.to_str()) {
+    match arg {
+      "--filetype" => filetype = args.get(i + 1).and_then(|arg| arg.to_str()),
+      _ if arg.starts_with("--filetype=") => filetype = arg.strip_prefix("--filetype="),
+      "--session" | "--kak-client" | "--format" | "--log-file" => (),
+      "label" | "doctor" => subcommand = Some(i),
+      _ if arg.starts_with('-') => {
+        i += 1;
+        continue;
+      }
+      _ => break,
     }
-  }
 
-  fn process(self) -> Response {
-    // if we don’t have any label / no key is set, then we are tasked to generate the labels first
-    match self.key {
-      None => Self::generate_labels(self.sels, self.keyset, self.handle),
-      Some(key) => Self::reduce(self.sels, self.labels, key, self.handle),
+    if subcommand.is_some() {
+      break;
     }
+
+    i += if arg.contains('=') { 1 } else { 2 };
   }
 
-  fn generate_labels(sels: Vec<Sel>, keyset: Vec<char>, handle: Handle) -> Response {
-    let mut trie = Trie::default();
-    trie.grow_repeatedly(sels.len(), &keyset);
+  let Some(subcommand) = subcommand else {
+    return args;
+  };
+  let Some(config) = config_path().and_then(|path| fs::read_to_string(path).ok()) else {
+    return args;
+  };
+
+  let config = config_args(&config, filetype);
+  args.splice(
+    subcommand + 1..subcommand + 1,
+    config.into_iter().map(OsString::from),
+  );
+  args
+}
 
-    let replace_ranges = trie
-      .labels()
-      .into_iter()
-      .zip(sels)
-      .map(|(label, sel)| ReplaceRange::new(sel, label))
-      .collect();
+/// Path to this binary, for Kakoune to call us back.
+fn bin() -> String {
+  std::env::current_exe()
+    .expect("cannot find the hop-kak binary")
+    .display()
+    .to_string()
+}
 
-    Response::LabelsGenerated {
-      replace_ranges,
-      handle,
+/// Timings of the stages of a hop.
+mod bench {
+  use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+  };
+
+  use clap::Parser;
+  use hop_core::{Action, App, Callback, Format, Input, Keyset, Options, Response, Trie};
+
+  use crate::{bin, Cli, Command};
+
+  /// Mean duration of `iterations` runs of `stage`, each on a fresh value from `setup`, which is not timed.
+  fn measure<S, T>(
+    iterations: usize,
+    mut setup: impl FnMut() -> S,
+    mut stage: impl FnMut(S) -> T,
+  ) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..iterations {
+      let input = setup();
+      let start = Instant::now();
+      black_box(stage(input));
+      total += start.elapsed();
     }
-  }
 
-  fn reduce(sels: Vec<Sel>, labels: Vec<String>, key: String, handle: Handle) -> Response {
-    if key == "<esc>" {
-      return Response::Cleanup;
-    }
+    total / iterations as u32
+  }
 
-    let replace_ranges = sels
-      .into_iter()
-      .zip(labels)
-      .filter_map(|(sel, label)| {
-        label
-          .strip_prefix(&key)
-          .map(|label| ReplaceRange::new(sel, label.to_owned()))
+  /// Time growing the trie, generating the labels and rendering the response for `targets` selections.
+  pub fn run(
+    targets: usize,
+    iterations: usize,
+    options: &Options,
+    format: Format,
+  ) -> Result<(), String> {
+    let keyset = Keyset::from_options(options);
+    keyset.validate()?;
+
+    // one-character selections, ten per line, as hinted words would be
+    let sels: Vec<_> = (0..targets)
+      .map(|i| {
+        let (line, col) = (i / 10 + 1, i % 10 * 8 + 1);
+        format!("{line}.{col},{line}.{col}")
       })
       .collect();
+    let sels = sels.join(" ");
+    let label = match Cli::try_parse_from(["hop-kak", "label", "--sels", &sels]) {
+      Ok(Cli {
+        command: Some(Command::Label { label, .. }),
+        ..
+      }) => label,
+      _ => unreachable!("generated label arguments are valid"),
+    };
+    let app = || {
+      App::new(
+        options.clone(),
+        Action::Label(label.clone()),
+        Input::Forwarded(Vec::new()),
+      )
+    };
+
+    if let Response::Fail { message } = app().process() {
+      return Err(message);
+    }
 
-    Response::Reduced {
-      replace_ranges,
-      handle,
+    let callback = Callback::Process { bin: bin() };
+    let stages = [
+      (
+        "trie growth",
+        measure(iterations, Trie::default, |mut trie| {
+          trie.grow_repeatedly(targets, &keyset);
+          trie.labels()
+        }),
+      ),
+      ("label generation", measure(iterations, app, App::process)),
+      (
+        "rendering",
+        measure(
+          iterations,
+          || app().process(),
+          |resp| {
+            let mut out = Vec::new();
+            resp.write(&mut out, &callback, format).map(|_| out)
+          },
+        ),
+      ),
+    ];
+
+    println!("{targets} targets, mean of {iterations} runs");
+    for (stage, duration) in stages {
+      println!(
+        "{stage:<18}{:>10.3} ms{:>14.0} targets/s",
+        duration.as_secs_f64() * 1000.,
+        targets as f64 / duration.as_secs_f64().max(f64::EPSILON),
+      );
     }
+
+    Ok(())
   }
 }
 
-#[derive(Debug)]
-enum Response {
-  Cleanup,
-  LabelsGenerated {
-    replace_ranges: Vec<ReplaceRange>,
-    handle: Handle,
-  },
-  Reduced {
-    replace_ranges: Vec<ReplaceRange>,
-    handle: Handle,
-  },
-}
+/// Checks of the setup of a Kakoune session.
+mod doctor {
+  use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use hop_core::{sh_quote, validate_prefix, Keyset, KeysetPreset, Options};
+
+  /// How long Kakoune is waited for before giving up.
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  /// Last line written by Kakoune, once every check is done.
+  const DONE: &str = "hop-kak-doctor-done";
+
+  /// Kakoune commands appending what the checks need to know to `path`, one fact per line, and [`DONE`] at
+  /// last.
+  fn probe(prefix: &str, path: &Path) -> String {
+    let path = sh_quote(&path.display().to_string());
+    let mut commands = String::new();
+
+    for option in ["ranges", "targets", "active", "modeline", "last", "repeat"] {
+      commands.push_str(&format!(
+        "try %{{ nop %opt{{{prefix}_{option}}}; nop %sh{{ echo 'option {prefix}_{option}' >>{path} }} }}\n"
+      ));
+    }
 
-impl Response {
-  fn set_handle(handle: Handle) {
-    match handle {
-      Handle::Anchor => println!("execute-keys '<a-:><a-;>'"),
-      Handle::Cursor => println!("execute-keys <a-:>"),
+    // the ranges highlighters check the type of their option when added
+    for (highlighter, option) in [("replace-ranges", "ranges"), ("ranges", "targets")] {
+      commands.push_str(&format!(
+        "try %{{ add-highlighter global/{prefix}-doctor {highlighter} {prefix}_{option}; \
+         remove-highlighter global/{prefix}-doctor; \
+         nop %sh{{ echo 'highlighter {prefix}_{option}' >>{path} }} }}\n"
+      ));
     }
+
+    // faces cannot be expanded, but they are listed in the debug buffer
+    commands.push_str(&format!(
+      "debug faces\n\
+       evaluate-commands -buffer *debug* %{{ execute-keys '%'; nop %sh{{ printf '%s\\n' \"$kak_selection\" >>{path} }} }}\n\
+       nop %sh{{ printf 'bin %s\\n' \"$(command -v hop-kak)\" >>{path}; echo {DONE} >>{path} }}\n"
+    ));
+
+    commands
   }
 
-  fn display_replace_ranges(replace_ranges: &[ReplaceRange], handle: Handle) {
-    print!("set-option window hop_ranges %val{{timestamp}} ");
-
-    for range in replace_ranges {
-      let sel = &range.sel;
-      let label = &range.label;
-      let label_len = label
-        .graphemes(true)
-        .count()
-        .min(sel.end.col - sel.start.col + 1);
-      let mut graphemes = label.graphemes(true).take(label_len);
-
-      // always display the first grapheme differently
-      if let Some(head) = graphemes.next() {
-        print!(
-          "{start_line}.{start_col}+1|{{hop_label_head}}{head} ",
-          start_line = sel.start.line,
-          start_col = if handle == Handle::Anchor {
-            sel.start.col
-          } else {
-            sel.end.col - label_len + 1
-          },
-        );
-
-        let tail: String = graphemes.collect();
-
-        if !tail.is_empty() {
-          print!(
-            "{start_line}.{start_col}+{label_len}|{{hop_label_tail}}{tail} ",
-            start_line = sel.start.line,
-            start_col = if handle == Handle::Anchor {
-              sel.start.col + 1
-            } else {
-              sel.end.col - label_len + 2
-            },
-            label_len = label_len - 1
-          );
-        }
+  /// Check the setup of a Kakoune session and print a report; return whether everything is fine.
+  pub fn run(session: &str, options: &Options) -> io::Result<bool> {
+    let prefix = options.prefix.as_deref().unwrap_or("hop");
+    let mut problems = Vec::new();
+    let mut report = |check: String, problem: Option<String>| {
+      match &problem {
+        None => println!("ok    {check}"),
+        Some(problem) => println!("FAIL  {check}: {problem}"),
+      }
+      problems.extend(problem);
+    };
+
+    report(
+      format!(
+        "keyset {}",
+        options
+          .keyset
+          .as_deref()
+          .or(options.keyset_preset.map(KeysetPreset::keys))
+          .unwrap_or(KeysetPreset::QwertyHome.keys())
+      ),
+      Keyset::from_options(options).validate().err(),
+    );
+    let invalid_prefix = validate_prefix(prefix).err();
+    let abort = invalid_prefix.is_some();
+    report(format!("prefix {prefix}"), invalid_prefix);
+    if abort {
+      return Ok(false);
+    }
+
+    let path = std::env::temp_dir().join(format!("hop-kak-doctor-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let kak = Command::new("kak")
+      .arg("-p")
+      .arg(session)
+      .stdin(Stdio::piped())
+      .spawn();
+    let mut kak = match kak {
+      Ok(kak) => kak,
+      Err(err) => {
+        println!("FAIL  kak: cannot run it: {err}");
+        return Ok(false);
+      }
+    };
+    kak
+      .stdin
+      .take()
+      .expect("piped stdin")
+      .write_all(probe(prefix, &path).as_bytes())?;
+    if !kak.wait()?.success() {
+      println!("FAIL  session {session}: cannot send commands to it");
+      return Ok(false);
+    }
+
+    // commands sent with -p run asynchronously
+    let start = Instant::now();
+    let facts = loop {
+      let facts = fs::read_to_string(&path).unwrap_or_default();
+      if facts.lines().any(|line| line == DONE) {
+        break facts;
+      }
+
+      if start.elapsed() > TIMEOUT {
+        println!("FAIL  session {session}: no answer from Kakoune");
+        let _ = fs::remove_file(&path);
+        return Ok(false);
       }
+
+      thread::sleep(Duration::from_millis(50));
+    };
+    let _ = fs::remove_file(&path);
+
+    let declared = |fact: &str| facts.lines().any(|line| line == fact);
+    for option in ["ranges", "targets", "active", "modeline", "last", "repeat"] {
+      let option = format!("{prefix}_{option}");
+      report(
+        format!("option {option}"),
+        (!declared(&format!("option {option}"))).then(|| {
+          "not declared; is the output of `hop-kak init` evaluated in your kakrc?".to_owned()
+        }),
+      );
+    }
+
+    for option in ["ranges", "targets"] {
+      let option = format!("{prefix}_{option}");
+      report(
+        format!("highlighter of {option}"),
+        (!declared(&format!("highlighter {option}")))
+          .then(|| "cannot be added; the option must be declared as range-specs".to_owned()),
+      );
     }
 
-    println!();
+    for face in ["label_head", "label_tail", "target", "dim"] {
+      let face = format!("{prefix}_{face}");
+      let set = facts.lines().any(|line| {
+        line
+          .trim_start_matches([' ', '*'])
+          .starts_with(&format!("{face}:"))
+      });
+      report(
+        format!("face {face}"),
+        (!set).then(|| "not set; set it in your kakrc or with `hop-kak init`".to_owned()),
+      );
+    }
+
+    let bin = facts
+      .lines()
+      .find_map(|line| line.strip_prefix("bin "))
+      .unwrap_or_default();
+    let found = fs::canonicalize(bin).ok();
+    let running = std::env::current_exe().and_then(fs::canonicalize).ok();
+    report(
+      format!("binary {bin}"),
+      match (found, running) {
+        (None, _) => Some("hop-kak is not in the PATH of Kakoune".to_owned()),
+        (found, running) if found != running => running.map(|running| {
+          format!(
+            "Kakoune calls another hop-kak than this one, {}",
+            running.display()
+          )
+        }),
+        _ => None,
+      },
+    );
+
+    Ok(problems.is_empty())
   }
+}
 
-  fn display_cleanup() {
-    println!("try %{{ remove-highlighter window/hop-ranges }}");
+/// Daemon mode, keeping the state of a hinting session in memory, and its client.
+#[cfg(unix)]
+mod daemon {
+  use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::{
+      ffi::OsStrExt,
+      fs::{DirBuilderExt, MetadataExt},
+      net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    time::Duration,
+  };
+
+  use clap::Parser;
+  use hop_core::{Action, App, Callback, Format, Input, ReplaceRange, Response};
+
+  use crate::{
+    bin, invalid_args, with_config, with_kak_env, with_seed, Cli, Command, Log, KAK_ENV,
+  };
+
+  /// Time a client has to send its request and read the response, so that a stuck one doesn't block the others.
+  const TIMEOUT: Duration = Duration::from_secs(2);
+
+  /// User we run as.
+  fn uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }
   }
 
-  fn display_reduce_callback(replace_ranges: &[ReplaceRange], handle: Handle) {
-    if replace_ranges.len() == 1 {
-      Self::display_cleanup();
-      return;
+  /// Path of the socket of the daemon serving a Kakoune session.
+  ///
+  /// Sockets live in a directory only accessible by the current user, so that another one cannot serve our requests.
+  fn socket_path(session: &str) -> io::Result<PathBuf> {
+    let uid = uid();
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+      .map(PathBuf::from)
+      .unwrap_or_else(std::env::temp_dir)
+      .join(format!("hop-kak-{uid}"));
+
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+      Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+      _ => (),
     }
 
-    let sels: Vec<_> = replace_ranges.iter().map(|r| r.sel.to_str()).collect();
-    let sels = sels.join(" ");
-    let labels: Vec<_> = replace_ranges.iter().map(|r| r.label.as_str()).collect();
-    let labels = labels.join(" ");
+    // the directory might have been created by someone else before us
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is not a private directory of ours", dir.display()),
+      ));
+    }
 
-    println!(
-      r#"on-key 'evaluate-commands -save-regs ^ -no-hooks -- %sh{{ {bin} --handle {handle} --sels "{sels}" --labels "{labels}" --key $kak_key }}'"#,
-      bin = std::env::current_exe().unwrap().display()
-    );
+    Ok(dir.join(format!("{session}.sock")))
   }
 
-  fn display_new_sels(replace_ranges: &[ReplaceRange]) {
-    print!(r#"set-register ^ "%val{{buffile}}@%val{{timestamp}}@0" "#);
-    for range in replace_ranges {
-      print!("{} ", range.sel.to_str());
+  /// Serve requests for a Kakoune session until asked to stop.
+  pub fn serve(session: &str) -> io::Result<()> {
+    let path = socket_path(session)?;
+
+    // a previous daemon might have left its socket behind
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+      if metadata.uid() != uid() {
+        return Err(io::Error::new(
+          io::ErrorKind::PermissionDenied,
+          format!("{} is not ours", path.display()),
+        ));
+      }
+
+      fs::remove_file(&path)?;
     }
-    println!();
 
-    println!("execute-keys z");
+    let listener = UnixListener::bind(&path)?;
+    let mut states = HashMap::new();
+
+    for stream in listener.incoming() {
+      let stream = stream?;
+      stream.set_read_timeout(Some(TIMEOUT))?;
+      stream.set_write_timeout(Some(TIMEOUT))?;
+
+      match handle(stream, session, &mut states) {
+        Ok(true) => continue,
+        Ok(false) => break,
+        Err(err) => eprintln!("cannot handle request: {err}"),
+      }
+    }
+
+    fs::remove_file(&path)
   }
 
-  fn into_stdout(self) {
-    match self {
-      Self::Cleanup => Self::display_cleanup(),
+  /// Handle a single request.
+  ///
+  /// The hints of each Kakoune client are kept apart in `states`. Return `false` if the daemon must stop.
+  pub fn handle(
+    mut stream: UnixStream,
+    session: &str,
+    states: &mut HashMap<Option<String>, Vec<ReplaceRange>>,
+  ) -> io::Result<bool> {
+    let callback = |client| Callback::Daemon {
+      bin: bin(),
+      session: session.to_owned(),
+      client,
+    };
+
+    let mut request = Vec::new();
+    stream.read_to_end(&mut request)?;
+
+    // the arguments come first, then the variables of Kakoune of the client as `name=value`, then the standard input
+    let (args, input) = entries(&request)?;
+    let (env, input) = entries(input)?;
+    let args = args
+      .into_iter()
+      .map(|arg| OsStr::from_bytes(arg).to_owned())
+      .collect();
+    let env: HashMap<_, _> = env
+      .into_iter()
+      .filter_map(|var| {
+        let var = String::from_utf8_lossy(var);
+        let (name, value) = var.split_once('=')?;
+        Some((name.to_owned(), value.to_owned()))
+      })
+      .collect();
+
+    let args = with_config(args);
+    let cli = match Cli::try_parse_from(args.clone()) {
+      Ok(cli) => cli,
+      Err(err) => {
+        eprintln!("{err}");
+        invalid_args(&err).write(&mut stream, &callback(None), Format::default())?;
+        return Ok(true);
+      }
+    };
+
+    if cli.stop {
+      return Ok(false);
+    }
 
-      Self::LabelsGenerated {
-        replace_ranges,
-        handle,
-      } => {
-        Self::display_cleanup();
+    let client = cli.kak_client;
+    let callback = callback(client.clone());
 
-        println!("add-highlighter window/hop-ranges replace-ranges hop_ranges");
+    let Some((mut options, mut action)) = cli.command.and_then(Command::into_hop) else {
+      let message = "the daemon only serves label and reduce requests".to_owned();
+      Response::Fail { message }.write(&mut stream, &callback, Format::default())?;
+      return Ok(true);
+    };
 
-        Self::display_replace_ranges(&replace_ranges, handle);
-        Self::set_handle(handle);
-        Self::display_reduce_callback(&replace_ranges, handle);
+    with_kak_env(&mut options, &mut action, |name| env.get(name).cloned());
+    with_seed(&mut options);
+
+    // reductions only send the key; selections and labels are the ones we kept from the previous request
+    let restore = matches!(&action, Action::Reduce(reduce) if reduce.restore);
+    let format = cli.format.unwrap_or_default();
+    let logged_input = cli.log_file.as_ref().map(|_| input.to_vec());
+    let mut app = App::new(options, action, Input::Forwarded(input.to_vec()));
+
+    if restore {
+      if let Some(replace_ranges) = states.remove(&client) {
+        app.restore(replace_ranges);
       }
+    }
 
-      Self::Reduced {
-        replace_ranges,
-        handle,
-      } => {
-        Self::display_replace_ranges(&replace_ranges, handle);
-        Self::display_new_sels(&replace_ranges);
-        Self::set_handle(handle);
-        Self::display_reduce_callback(&replace_ranges, handle);
+    let resp = app.process();
+    match resp.replace_ranges() {
+      Some(replace_ranges) => states.insert(client, replace_ranges.to_vec()),
+      None => states.remove(&client),
+    };
+    match (&cli.log_file, &logged_input) {
+      (Some(path), Some(input)) => Log {
+        path,
+        args: &args,
+        input,
       }
+      .write(resp, &mut stream, &callback, format)?,
+      _ => resp.write(&mut stream, &callback, format)?,
     }
-  }
-}
 
-#[derive(Debug)]
-struct ReplaceRange {
-  sel: Sel,
-  label: String,
-}
+    Ok(true)
+  }
 
-impl ReplaceRange {
-  fn new(sel: Sel, label: impl Into<String>) -> Self {
-    Self {
-      sel,
-      label: label.into(),
+  /// Entries at the start of `request`, as written by [`write_entries`], and the rest of `request`.
+  fn entries(request: &[u8]) -> io::Result<(Vec<&[u8]>, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed request");
+    let mut fields = request.splitn(2, |&b| b == 0);
+    let count: usize = std::str::from_utf8(fields.next().ok_or_else(invalid)?)
+      .ok()
+      .and_then(|count| count.parse().ok())
+      .ok_or_else(invalid)?;
+    let mut request = fields.next().ok_or_else(invalid)?;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+      let i = request.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+      entries.push(&request[..i]);
+      request = &request[i + 1..];
     }
+
+    Ok((entries, request))
   }
-}
 
-fn main() {
-  let cli = Cli::parse();
+  /// Write the number of `entries`, then each of them, NUL-terminated; entries may be empty but cannot contain NUL.
+  pub fn write_entries(out: &mut impl Write, entries: &[OsString]) -> io::Result<()> {
+    write!(out, "{}\0", entries.len())?;
+    for entry in entries {
+      out.write_all(entry.as_bytes())?;
+      out.write_all(b"\0")?;
+    }
 
-  #[cfg(feature = "init")]
-  if cli.init {
-    print!("{}", RC);
-    return;
+    Ok(())
   }
 
-  let app = App::new(cli);
+  /// Forward our arguments, the variables of Kakoune we read and our standard input to the daemon of a Kakoune
+  /// session, and write back its response.
+  pub fn forward(session: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path(session)?)?;
+
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let env: Vec<OsString> = KAK_ENV
+      .iter()
+      .filter_map(|name| {
+        let mut var = OsString::from(format!("{name}="));
+        var.push(std::env::var_os(name)?);
+        Some(var)
+      })
+      .collect();
+    write_entries(&mut stream, &args)?;
+    write_entries(&mut stream, &env)?;
 
-  let resp = app.process();
-  resp.into_stdout();
+    io::copy(&mut io::stdin(), &mut stream)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    io::copy(&mut stream, &mut io::stdout())?;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::Trie;
+  use std::{collections::HashMap, ffi::OsString, fs};
 
-  #[test]
-  fn iter() {
-    let keyset = "abcd".chars().collect::<Vec<_>>();
+  use clap::{CommandFactory, Parser};
+  use hop_core::{Action, Options};
 
-    let mut trie = Trie::default();
-    trie.grow_repeatedly(4, &keyset);
-    let hints = trie.labels();
-    assert_eq!(hints, vec!["a", "b", "c", "d"]);
+  use crate::{config_path, with_config, with_kak_env, Cli, Command};
+  #[cfg(feature = "init")]
+  use crate::{FacePreset, InitOptions};
+
+  /// Options and action of a hop run with `args`.
+  fn hop(args: Vec<OsString>) -> (Options, Action) {
+    Cli::try_parse_from(args)
+      .unwrap()
+      .command
+      .and_then(Command::into_hop)
+      .unwrap()
+  }
+
+  /// Command line of `args`.
+  fn os_args(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+  }
+
+  #[test]
+  fn cli() {
+    // catches clashing arguments, such as `-h` for both `--handle` and `--help`
+    Cli::command().debug_assert();
+  }
 
-    let mut trie = Trie::default();
-    trie.grow_repeatedly(10, &keyset);
-    let hints = trie.labels();
+  #[test]
+  fn config_precedence() {
+    let path = std::env::temp_dir().join(format!("hop-kak-config-{}", std::process::id()));
+    fs::write(
+      &path,
+      "--keyset\nabc\n--semantic\n\n[markdown]\n--keyset\netisura\n",
+    )
+    .unwrap();
+    std::env::set_var("HOP_KAK_CONFIG", &path);
+    assert_eq!(config_path(), Some(path.clone()));
+
+    // the configuration goes right after `label`, so that the command line overrides it
+    let args = with_config(os_args(&[
+      "hop-kak",
+      "--filetype",
+      "markdown",
+      "label",
+      "-k",
+      "xy",
+    ]));
     assert_eq!(
-      hints,
-      vec!["a", "b", "ca", "cb", "cc", "cd", "da", "db", "dc", "dd"]
+      args,
+      os_args(&[
+        "hop-kak",
+        "--filetype",
+        "markdown",
+        "label",
+        "--keyset",
+        "abc",
+        "--semantic",
+        "--keyset",
+        "etisura",
+        "-k",
+        "xy",
+      ])
+    );
+    let (options, _) = hop(args);
+    assert_eq!(options.keyset.as_deref(), Some("xy"));
+    assert!(options.semantic);
+
+    let (options, _) = hop(with_config(os_args(&[
+      "hop-kak",
+      "--filetype=markdown",
+      "label",
+    ])));
+    assert_eq!(options.keyset.as_deref(), Some("etisura"));
+
+    // doctor checks the options of the configuration
+    let cli = Cli::try_parse_from(with_config(os_args(&[
+      "hop-kak",
+      "--session",
+      "session",
+      "doctor",
+    ])))
+    .unwrap();
+    let Some(Command::Doctor { options, .. }) = cli.command else {
+      panic!("not a doctor command");
+    };
+    assert_eq!(options.keyset.as_deref(), Some("abc"));
+
+    // reductions do not read it
+    let args = os_args(&[
+      "hop-kak", "reduce", "-z", "a", "--sels", "1.1,1.1", "--labels", "a",
+    ]);
+    assert_eq!(with_config(args.clone()), args);
+
+    std::env::remove_var("HOP_KAK_CONFIG");
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn kak_env() {
+    let env: HashMap<_, _> = [
+      ("kak_selections_desc", "1.1,1.5 2.1,2.3"),
+      ("kak_timestamp", "12"),
+      ("kak_window_range", "9 0 20 80"),
+    ]
+    .into_iter()
+    .collect();
+    let var = |name: &str| env.get(name).map(|value| value.to_string());
+
+    let (mut options, mut action) = hop(os_args(&["hop-kak", "label"]));
+    with_kak_env(&mut options, &mut action, var);
+    let Action::Label(label) = action else {
+      panic!("not a label action");
+    };
+    assert_eq!(label.sels.as_deref(), Some("1.1,1.5 2.1,2.3"));
+    assert_eq!(options.timestamp, Some(12));
+    let view_range = label.view_range.unwrap();
+    assert!(!view_range.contains(9) && view_range.contains(10) && view_range.contains(29));
+    assert!(!view_range.contains(30));
+
+    // the command line comes first, and targets found in the text are not replaced by the selections
+    let (mut options, mut action) = hop(os_args(&[
+      "hop-kak",
+      "label",
+      "--words",
+      "--timestamp",
+      "3",
+    ]));
+    with_kak_env(&mut options, &mut action, var);
+    let Action::Label(label) = action else {
+      panic!("not a label action");
+    };
+    assert_eq!(label.sels, None);
+    assert_eq!(options.timestamp, Some(3));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn daemon_round_trip() {
+    use std::{io::Read, net::Shutdown, os::unix::net::UnixStream};
+
+    use crate::daemon::{handle, write_entries};
+
+    let mut states = HashMap::new();
+    let mut request = |args: &[&str]| {
+      let (mut client, server) = UnixStream::pair().unwrap();
+      write_entries(&mut client, &os_args(args)).unwrap();
+      write_entries(&mut client, &os_args(&["kak_timestamp=1"])).unwrap();
+      client.shutdown(Shutdown::Write).unwrap();
+
+      assert!(handle(server, "session", &mut states).unwrap());
+      let mut resp = String::new();
+      client.read_to_string(&mut resp).unwrap();
+      resp
+    };
+
+    // the first key of a hop is sent with an empty `--typed`
+    let resp = request(&[
+      "hop-kak",
+      "--client",
+      "--session",
+      "session",
+      "--kak-client",
+      "client0",
+      "reduce",
+      "--keyset",
+      "ab",
+      "--sels",
+      "1.1,1.1 2.1,2.1 3.1,3.1",
+      "--labels",
+      "a ba bb",
+      "--typed",
+      "",
+      "--key",
+      "b",
+    ]);
+    assert!(!resp.contains("fail"), "{resp}");
+    assert!(resp.contains("--typed ''b''"), "{resp}");
+    assert!(resp.contains(" --restore "), "{resp}");
+
+    // the next key is resolved from the hints kept for the client
+    let resp = request(&[
+      "hop-kak",
+      "--client",
+      "--session",
+      "session",
+      "--kak-client",
+      "client0",
+      "reduce",
+      "--keyset",
+      "ab",
+      "--typed",
+      "b",
+      "--restore",
+      "--key",
+      "b",
+    ]);
+    assert!(resp.contains("HopJump=3.1,3.1"), "{resp}");
+  }
+
+  #[cfg(feature = "init")]
+  #[test]
+  fn prefixed_rc() {
+    let init = InitOptions {
+      no_default_mappings: false,
+      user_mode_name: "hop".to_owned(),
+      declare_faces: true,
+      faces: FacePreset::Default,
+      prefix: "jump".to_owned(),
+    };
+    let rc = init.rc();
+
+    assert!(rc.contains("declare-option range-specs jump_ranges"));
+    assert!(rc.contains("set-face global jump_label_head "));
+    assert!(rc.contains("\"$kak_client\"} label --prefix jump "));
+    assert!(rc.contains("set-option global jump_daemon %val{session}"));
+    assert!(rc.contains("define-command jump-again "));
+    assert!(rc.contains("map global hop a ':jump-again<ret>'"));
+    assert!(rc.contains("hook -group jump-kak-daemon "));
+
+    // only the binary keeps its name
+    let idents: Vec<_> = rc
+      .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+      .filter(|ident| {
+        (ident.starts_with("hop_") || ident.starts_with("hop-")) && *ident != "hop-kak"
+      })
+      .collect();
+    assert_eq!(idents, Vec::<&str>::new());
+  }
+
+  #[cfg(feature = "init")]
+  #[test]
+  fn default_rc() {
+    let init = InitOptions {
+      no_default_mappings: false,
+      user_mode_name: "hop".to_owned(),
+      declare_faces: true,
+      faces: FacePreset::Default,
+      prefix: "hop".to_owned(),
+    };
+
+    assert!(
+      init.rc() == include_str!("../hop.kak"),
+      "hop.kak is not hop.kak.in rendered with the default options"
     );
   }
 }